@@ -0,0 +1,1109 @@
+use dsp::Dsp;
+
+bitflags! {
+    #[derive(Default)]
+    pub flags Psw: u8 {
+        const PSW_C = 0b00000001,
+        const PSW_Z = 0b00000010,
+        const PSW_I = 0b00000100,
+        const PSW_H = 0b00001000,
+        const PSW_B = 0b00010000,
+        const PSW_P = 0b00100000,
+        const PSW_V = 0b01000000,
+        const PSW_N = 0b10000000,
+    }
+}
+
+// The boot ROM, mapped over $FFC0-$FFFF while CONTROL bit 7 is set.
+// It clears the zero page, says $BBAA on the ports and waits for
+// The CPU to upload blocks of code.
+pub static IPL_ROM: [u8; 64] = [
+    0xCD, 0xEF, 0xBD, 0xE8, 0x00, 0xC6, 0x1D, 0xD0,
+    0xFC, 0x8F, 0xAA, 0xF4, 0x8F, 0xBB, 0xF5, 0x78,
+    0xCC, 0xF4, 0xD0, 0xFB, 0x2F, 0x19, 0xEB, 0xF4,
+    0xD0, 0xFC, 0x7E, 0xF4, 0xD0, 0x0B, 0xE4, 0xF5,
+    0xCB, 0xF4, 0xD7, 0x00, 0xFC, 0xD0, 0xF3, 0xAB,
+    0x01, 0x10, 0xEF, 0x7E, 0xF4, 0x10, 0xEB, 0xBA,
+    0xF6, 0xDA, 0x00, 0xBA, 0xF4, 0xC4, 0xF4, 0xDD,
+    0x5D, 0xD0, 0xDB, 0x1F, 0x00, 0x00, 0xC0, 0xFF,
+];
+
+// The SPC700 runs off a 24.576 MHz crystal divided by 24,
+// The master clock is 21.477 MHz NTSC and 21.281 MHz PAL
+const APU_CLOCK: i64 = 1_024_000;
+const NTSC_CLOCK: i64 = 21_477_272;
+const PAL_CLOCK: i64 = 21_281_370;
+
+// Timers 0 and 1 tick at 8 kHz, timer 2 at 64 kHz
+const TIMER_CYCLES: u32 = 16;
+
+// The DSP puts out a sample every 32 cycles, 32 kHz
+const SAMPLE_CYCLES: u32 = 32;
+
+#[derive(Debug, Clone, Default)]
+struct Timer {
+    enabled: bool,
+    target: u8,
+    stage: u8,
+    out: u8,
+}
+
+impl Timer {
+    // A target of 0 counts to 256
+    fn tick(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        self.stage = self.stage.wrapping_add(1);
+        if self.stage == self.target {
+            self.stage = 0;
+            self.out = (self.out + 1) & 0xF;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Apu {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub psw: Psw,
+    pub aram: Vec<u8>,
+    pub pal: bool,
+    pub cycles: u64,
+    control: u8,
+    pub dsp: Dsp,
+    // Interleaved stereo samples not yet played
+    pub samples: Vec<i16>,
+    dsp_addr: u8,
+    dsp_cycles: u32,
+    // $2140-$2143 as written by the CPU and by the SPC700
+    ports_in: [u8; 4],
+    ports_out: [u8; 4],
+    timers: [Timer; 3],
+    timer_cycles: u32,
+    timer_ticks: u32,
+    clock_debt: i64,
+    stopped: bool,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            a: 0u8,
+            x: 0u8,
+            y: 0u8,
+            sp: 0u8,
+            pc: 0u16,
+            psw: Psw::empty(),
+            aram: vec![0u8; 0x10000],
+            pal: false,
+            cycles: 0u64,
+            control: 0u8,
+            dsp: Dsp::new(),
+            samples: Vec::new(),
+            dsp_addr: 0u8,
+            dsp_cycles: 0u32,
+            ports_in: [0u8; 4],
+            ports_out: [0u8; 4],
+            timers: Default::default(),
+            timer_cycles: 0u32,
+            timer_ticks: 0u32,
+            clock_debt: 0i64,
+            stopped: false,
+        };
+        apu.reset();
+        apu
+    }
+
+    pub fn reset(&mut self) {
+        // Timers off, input ports cleared, IPL ROM mapped
+        self.write_control(0xB0);
+
+        self.a = 0u8;
+        self.x = 0u8;
+        self.y = 0u8;
+        self.sp = 0xEFu8;
+        self.psw = PSW_Z;
+        self.ports_out = [0u8; 4];
+        self.clock_debt = 0i64;
+        self.dsp_cycles = 0u32;
+        self.dsp.reset();
+        self.stopped = false;
+
+        // Start at the reset vector of the IPL ROM
+        self.pc = self.read_u16(0xFFFE);
+    }
+
+    // The CPU side of the four I/O ports
+    pub fn cpu_read(&self, port: usize) -> u8 {
+        self.ports_out[port & 3]
+    }
+
+    pub fn cpu_write(&mut self, port: usize, val: u8) {
+        self.ports_in[port & 3] = val;
+    }
+
+    // Catch up with `clocks` master clocks of the main CPU
+    pub fn run(&mut self, clocks: u32) {
+        let master = if self.pal { PAL_CLOCK } else { NTSC_CLOCK };
+
+        self.clock_debt += clocks as i64 * APU_CLOCK;
+        while self.clock_debt > 0 {
+            let cycles = self.step();
+            self.tick(cycles);
+            self.clock_debt -= cycles as i64 * master;
+        }
+    }
+
+    // Runs on its own until `count` samples are waiting, for
+    // Playing without the rest of the SNES
+    pub fn run_samples(&mut self, count: usize) {
+        while self.samples.len() < count {
+            let cycles = self.step();
+            self.tick(cycles);
+        }
+    }
+
+    // Puts back the registers at $F0-$FF from a snapshot of them,
+    // The input ports hold what the SPC700 would read
+    pub fn load_io(&mut self, io: &[u8]) {
+        self.write_control(io[0x1] & 0x87);
+        self.dsp_addr = io[0x2];
+        for i in 0..4 {
+            self.ports_in[i] = io[0x4 + i];
+        }
+        for i in 0..3 {
+            self.timers[i].target = io[0xA + i];
+            self.timers[i].out = io[0xD + i] & 0xF;
+        }
+    }
+
+    // What a snapshot keeps of $F0-$FF, the inverse of `load_io`
+    pub fn save_io(&self) -> [u8; 16] {
+        let mut io = [0u8; 16];
+        io.copy_from_slice(&self.aram[0xF0..0x100]);
+
+        io[0x1] = self.control & 0x87;
+        io[0x2] = self.dsp_addr;
+        io[0x3] = self.dsp.read(self.dsp_addr & 0x7F);
+        for i in 0..4 {
+            io[0x4 + i] = self.ports_in[i];
+        }
+        for i in 0..3 {
+            io[0xA + i] = self.timers[i].target;
+            io[0xD + i] = self.timers[i].out;
+        }
+        io
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.timer_cycles += cycles as u32;
+        self.dsp_cycles += cycles as u32;
+
+        while self.dsp_cycles >= SAMPLE_CYCLES {
+            self.dsp_cycles -= SAMPLE_CYCLES;
+
+            let (left, right) = self.dsp.run(&mut self.aram);
+            self.samples.push(left);
+            self.samples.push(right);
+        }
+
+        while self.timer_cycles >= TIMER_CYCLES {
+            self.timer_cycles -= TIMER_CYCLES;
+            self.timer_ticks = self.timer_ticks.wrapping_add(1);
+
+            self.timers[2].tick();
+            if self.timer_ticks % 8 == 0 {
+                self.timers[0].tick();
+                self.timers[1].tick();
+            }
+        }
+    }
+
+    fn write_control(&mut self, val: u8) {
+        // Starting a timer resets its counters
+        for i in 0..3 {
+            let enabled = val & (1 << i) != 0;
+            if enabled && !self.timers[i].enabled {
+                self.timers[i].stage = 0;
+                self.timers[i].out = 0;
+            }
+            self.timers[i].enabled = enabled;
+        }
+
+        if val & 0x10 == 0x10 {
+            self.ports_in[0] = 0;
+            self.ports_in[1] = 0;
+        }
+        if val & 0x20 == 0x20 {
+            self.ports_in[2] = 0;
+            self.ports_in[3] = 0;
+        }
+
+        self.control = val;
+    }
+
+    fn dsp_read(&self, addr: u8) -> u8 {
+        self.dsp.read(addr)
+    }
+
+    fn dsp_write(&mut self, addr: u8, val: u8) {
+        // $80-$FF mirror $00-$7F but can't be written
+        if addr < 0x80 {
+            self.dsp.write(addr, val);
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x00F0 | 0x00F1 | 0x00FA...0x00FC => 0u8,
+            0x00F2 => self.dsp_addr,
+            0x00F3 => {
+                let addr = self.dsp_addr;
+                self.dsp_read(addr)
+            }
+            0x00F4...0x00F7 => self.ports_in[(addr - 0xF4) as usize],
+            // Reading a timer output clears it
+            0x00FD...0x00FF => {
+                let timer = &mut self.timers[(addr - 0xFD) as usize];
+                let out = timer.out;
+                timer.out = 0;
+                out
+            }
+            0xFFC0...0xFFFF if self.control & 0x80 == 0x80 => IPL_ROM[(addr - 0xFFC0) as usize],
+            _ => self.aram[addr as usize],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x00F0 => {
+                // TEST, only useful for testing the chip itself
+            }
+            0x00F1 => self.write_control(val),
+            0x00F2 => self.dsp_addr = val,
+            0x00F3 => {
+                let addr = self.dsp_addr;
+                self.dsp_write(addr, val);
+            }
+            0x00F4...0x00F7 => self.ports_out[(addr - 0xF4) as usize] = val,
+            0x00FA...0x00FC => self.timers[(addr - 0xFA) as usize].target = val,
+            _ => { }
+        }
+
+        // Writes always reach the RAM underneath, even below the IPL ROM
+        self.aram[addr as usize] = val;
+    }
+
+    fn read_u16(&mut self, addr: u16) -> u16 {
+        (self.read(addr) as u16) | ((self.read(addr.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let pc = self.pc;
+        self.pc = pc.wrapping_add(1);
+        self.read(pc)
+    }
+
+    fn fetch_u16(&mut self) -> u16 {
+        (self.fetch() as u16) | ((self.fetch() as u16) << 8)
+    }
+
+    // The P flag moves the direct page to $0100
+    fn dp(&self, addr: u8) -> u16 {
+        match self.psw.contains(PSW_P) {
+            true => 0x0100 | addr as u16,
+            false => addr as u16,
+        }
+    }
+
+    fn read_dp(&mut self, addr: u8) -> u8 {
+        let addr = self.dp(addr);
+        self.read(addr)
+    }
+
+    fn write_dp(&mut self, addr: u8, val: u8) {
+        let addr = self.dp(addr);
+        self.write(addr, val);
+    }
+
+    // Words in the direct page wrap around inside it
+    fn read_dp_u16(&mut self, addr: u8) -> u16 {
+        (self.read_dp(addr) as u16) | ((self.read_dp(addr.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn write_dp_u16(&mut self, addr: u8, val: u16) {
+        self.write_dp(addr, val as u8);
+        self.write_dp(addr.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    fn push(&mut self, val: u8) {
+        let sp = self.sp;
+        self.write(0x0100 | sp as u16, val);
+        self.sp = sp.wrapping_sub(1);
+    }
+
+    fn pop(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        let sp = self.sp;
+        self.read(0x0100 | sp as u16)
+    }
+
+    fn push_pc(&mut self) {
+        let pc = self.pc;
+        self.push((pc >> 8) as u8);
+        self.push(pc as u8);
+    }
+
+    fn pop_pc(&mut self) {
+        let low = self.pop() as u16;
+        let high = self.pop() as u16;
+        self.pc = low | (high << 8);
+    }
+
+    fn ya(&self) -> u16 {
+        ((self.y as u16) << 8) | self.a as u16
+    }
+
+    fn set_ya(&mut self, val: u16) {
+        self.y = (val >> 8) as u8;
+        self.a = val as u8;
+    }
+
+    fn set_nz(&mut self, val: u8) {
+        self.psw.set(PSW_N, val & 0x80 == 0x80);
+        self.psw.set(PSW_Z, val == 0);
+    }
+
+    fn set_nz16(&mut self, val: u16) {
+        self.psw.set(PSW_N, val & 0x8000 == 0x8000);
+        self.psw.set(PSW_Z, val == 0);
+    }
+
+    // Fetches the offset and takes the branch, 2 more cycles if taken
+    fn branch(&mut self, taken: bool, cycles: u8) -> u8 {
+        let offset = self.fetch() as i8;
+        if taken {
+            self.pc = self.pc.wrapping_add(offset as i16 as u16);
+            cycles + 2
+        } else {
+            cycles
+        }
+    }
+
+    fn adc(&mut self, a: u8, b: u8) -> u8 {
+        let carry = self.psw.contains(PSW_C) as u16;
+        let result = a as u16 + b as u16 + carry;
+        let val = result as u8;
+
+        self.psw.set(PSW_V, !(a ^ b) & (a ^ val) & 0x80 == 0x80);
+        self.psw.set(PSW_H, (a & 0xF) as u16 + (b & 0xF) as u16 + carry > 0xF);
+        self.psw.set(PSW_C, result > 0xFF);
+        self.set_nz(val);
+        val
+    }
+
+    // Subtraction is addition of the complement, carry means no borrow
+    fn sbc(&mut self, a: u8, b: u8) -> u8 {
+        self.adc(a, !b)
+    }
+
+    fn compare(&mut self, a: u8, b: u8) {
+        self.psw.set(PSW_C, a >= b);
+        self.set_nz(a.wrapping_sub(b));
+    }
+
+    // OR, AND, EOR, CMP, ADC and SBC share their encodings
+    fn alu(&mut self, op: u8, a: u8, b: u8) -> u8 {
+        match op {
+            0 => { let val = a | b; self.set_nz(val); val }
+            1 => { let val = a & b; self.set_nz(val); val }
+            2 => { let val = a ^ b; self.set_nz(val); val }
+            3 => { self.compare(a, b); a }
+            4 => self.adc(a, b),
+            _ => self.sbc(a, b),
+        }
+    }
+
+    // ASL, ROL, LSR, ROR, DEC and INC share theirs
+    fn modify(&mut self, op: u8, val: u8) -> u8 {
+        let carry = self.psw.contains(PSW_C) as u8;
+        let result = match op {
+            0 => { self.psw.set(PSW_C, val & 0x80 == 0x80); val << 1 }
+            1 => { self.psw.set(PSW_C, val & 0x80 == 0x80); (val << 1) | carry }
+            2 => { self.psw.set(PSW_C, val & 1 == 1); val >> 1 }
+            3 => { self.psw.set(PSW_C, val & 1 == 1); (val >> 1) | (carry << 7) }
+            4 => val.wrapping_sub(1),
+            _ => val.wrapping_add(1),
+        };
+        self.set_nz(result);
+        result
+    }
+
+    // Operand address of the A register forms in columns 4-7
+    fn operand(&mut self, op: u8) -> (u16, u8) {
+        match (op & 0x0F, op & 0x10 == 0x10) {
+            (0x4, false) => {
+                let addr = self.fetch();
+                (self.dp(addr), 3)
+            }
+            (0x4, true) => {
+                let addr = self.fetch().wrapping_add(self.x);
+                (self.dp(addr), 4)
+            }
+            (0x5, false) => (self.fetch_u16(), 4),
+            (0x5, true) => (self.fetch_u16().wrapping_add(self.x as u16), 5),
+            (0x6, false) => (self.dp(self.x), 3),
+            (0x6, true) => (self.fetch_u16().wrapping_add(self.y as u16), 5),
+            (0x7, false) => {
+                let ptr = self.fetch().wrapping_add(self.x);
+                (self.read_dp_u16(ptr), 6)
+            }
+            _ => {
+                let ptr = self.fetch();
+                (self.read_dp_u16(ptr).wrapping_add(self.y as u16), 6)
+            }
+        }
+    }
+
+    // The 13 bit address and bit number of the mem.bit forms
+    fn mem_bit(&mut self) -> (u16, u8) {
+        let val = self.fetch_u16();
+        (val & 0x1FFF, (val >> 13) as u8)
+    }
+
+    // Runs one instruction and returns the cycles it took
+    pub fn step(&mut self) -> u8 {
+        // SLEEP and STOP wait for a reset
+        if self.stopped {
+            return 2;
+        }
+
+        let op = self.fetch();
+        match op {
+            // ALU ops on A, memory to memory and immediate forms
+            0x00...0xBF if op & 0x0F >= 0x4 && op & 0x0F <= 0x9 => {
+                let alu = op >> 5;
+                match (op & 0x0F, op & 0x10 == 0x10) {
+                    (0x8, false) => {
+                        let val = self.fetch();
+                        let a = self.a;
+                        self.a = self.alu(alu, a, val);
+                        2
+                    }
+                    (0x8, true) => {
+                        let val = self.fetch();
+                        let addr = self.fetch();
+                        let addr = self.dp(addr);
+                        let dest = self.read(addr);
+                        let result = self.alu(alu, dest, val);
+                        if alu != 3 { self.write(addr, result); }
+                        5
+                    }
+                    (0x9, false) => {
+                        let src = self.fetch();
+                        let val = self.read_dp(src);
+                        let addr = self.fetch();
+                        let addr = self.dp(addr);
+                        let dest = self.read(addr);
+                        let result = self.alu(alu, dest, val);
+                        if alu != 3 { self.write(addr, result); }
+                        6
+                    }
+                    (0x9, true) => {
+                        let y = self.y;
+                        let val = self.read_dp(y);
+                        let addr = self.dp(self.x);
+                        let dest = self.read(addr);
+                        let result = self.alu(alu, dest, val);
+                        if alu != 3 { self.write(addr, result); }
+                        5
+                    }
+                    _ => {
+                        let (addr, cycles) = self.operand(op);
+                        let val = self.read(addr);
+                        let a = self.a;
+                        self.a = self.alu(alu, a, val);
+                        cycles
+                    }
+                }
+            }
+
+            // MOV A,mem and MOV mem,A
+            0xE4...0xE7 | 0xF4...0xF7 => {
+                let (addr, cycles) = self.operand(op);
+                let val = self.read(addr);
+                self.a = val;
+                self.set_nz(val);
+                cycles
+            }
+            0xC4...0xC7 | 0xD4...0xD7 => {
+                let (addr, cycles) = self.operand(op);
+                let a = self.a;
+                self.write(addr, a);
+                cycles + 1
+            }
+
+            // Shifts, rotates, DEC and INC
+            0x0B | 0x2B | 0x4B | 0x6B | 0x8B | 0xAB => {
+                let addr = self.fetch();
+                let addr = self.dp(addr);
+                let val = self.read(addr);
+                let result = self.modify(op >> 5, val);
+                self.write(addr, result);
+                4
+            }
+            0x1B | 0x3B | 0x5B | 0x7B | 0x9B | 0xBB => {
+                let addr = self.fetch().wrapping_add(self.x);
+                let addr = self.dp(addr);
+                let val = self.read(addr);
+                let result = self.modify(op >> 5, val);
+                self.write(addr, result);
+                5
+            }
+            0x0C | 0x2C | 0x4C | 0x6C | 0x8C | 0xAC => {
+                let addr = self.fetch_u16();
+                let val = self.read(addr);
+                let result = self.modify(op >> 5, val);
+                self.write(addr, result);
+                5
+            }
+            0x1C | 0x3C | 0x5C | 0x7C | 0x9C | 0xBC => {
+                let a = self.a;
+                self.a = self.modify(op >> 5, a);
+                2
+            }
+
+            // NOP and the flag instructions
+            0x00 => 2,
+            0x20 => { self.psw.remove(PSW_P); 2 }
+            0x40 => { self.psw.insert(PSW_P); 2 }
+            0x60 => { self.psw.remove(PSW_C); 2 }
+            0x80 => { self.psw.insert(PSW_C); 2 }
+            0xA0 => { self.psw.insert(PSW_I); 3 }
+            0xC0 => { self.psw.remove(PSW_I); 3 }
+            0xE0 => { self.psw.remove(PSW_V | PSW_H); 2 }
+            0xED => { self.psw.toggle(PSW_C); 3 }
+
+            // Conditional branches
+            0x10 => { let n = self.psw.contains(PSW_N); self.branch(!n, 2) }
+            0x30 => { let n = self.psw.contains(PSW_N); self.branch(n, 2) }
+            0x50 => { let v = self.psw.contains(PSW_V); self.branch(!v, 2) }
+            0x70 => { let v = self.psw.contains(PSW_V); self.branch(v, 2) }
+            0x90 => { let c = self.psw.contains(PSW_C); self.branch(!c, 2) }
+            0xB0 => { let c = self.psw.contains(PSW_C); self.branch(c, 2) }
+            0xD0 => { let z = self.psw.contains(PSW_Z); self.branch(!z, 2) }
+            0xF0 => { let z = self.psw.contains(PSW_Z); self.branch(z, 2) }
+            0x2F => self.branch(true, 2),
+
+            // TCALL n calls through the table below the IPL ROM
+            0x01 | 0x11 | 0x21 | 0x31 | 0x41 | 0x51 | 0x61 | 0x71 |
+            0x81 | 0x91 | 0xA1 | 0xB1 | 0xC1 | 0xD1 | 0xE1 | 0xF1 => {
+                let vector = 0xFFDE - ((op >> 4) as u16) * 2;
+                self.push_pc();
+                self.pc = self.read_u16(vector);
+                8
+            }
+
+            // SET1 and CLR1 dp.bit
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 |
+            0x82 | 0x92 | 0xA2 | 0xB2 | 0xC2 | 0xD2 | 0xE2 | 0xF2 => {
+                let bit = 1 << (op >> 5);
+                let addr = self.fetch();
+                let addr = self.dp(addr);
+                let val = self.read(addr);
+                match op & 0x10 {
+                    0 => self.write(addr, val | bit),
+                    _ => self.write(addr, val & !bit),
+                }
+                4
+            }
+
+            // BBS and BBC dp.bit,rel
+            0x03 | 0x13 | 0x23 | 0x33 | 0x43 | 0x53 | 0x63 | 0x73 |
+            0x83 | 0x93 | 0xA3 | 0xB3 | 0xC3 | 0xD3 | 0xE3 | 0xF3 => {
+                let bit = 1 << (op >> 5);
+                let addr = self.fetch();
+                let val = self.read_dp(addr);
+                let set = val & bit == bit;
+                self.branch(set == (op & 0x10 == 0), 5)
+            }
+
+            // Single bits of memory against the carry
+            0x0A => {
+                let (addr, bit) = self.mem_bit();
+                let val = (self.read(addr) >> bit) & 1 == 1;
+                let carry = self.psw.contains(PSW_C);
+                self.psw.set(PSW_C, carry | val);
+                5
+            }
+            0x2A => {
+                let (addr, bit) = self.mem_bit();
+                let val = (self.read(addr) >> bit) & 1 == 1;
+                let carry = self.psw.contains(PSW_C);
+                self.psw.set(PSW_C, carry | !val);
+                5
+            }
+            0x4A => {
+                let (addr, bit) = self.mem_bit();
+                let val = (self.read(addr) >> bit) & 1 == 1;
+                let carry = self.psw.contains(PSW_C);
+                self.psw.set(PSW_C, carry & val);
+                4
+            }
+            0x6A => {
+                let (addr, bit) = self.mem_bit();
+                let val = (self.read(addr) >> bit) & 1 == 1;
+                let carry = self.psw.contains(PSW_C);
+                self.psw.set(PSW_C, carry & !val);
+                4
+            }
+            0x8A => {
+                let (addr, bit) = self.mem_bit();
+                let val = (self.read(addr) >> bit) & 1 == 1;
+                let carry = self.psw.contains(PSW_C);
+                self.psw.set(PSW_C, carry ^ val);
+                5
+            }
+            0xAA => {
+                let (addr, bit) = self.mem_bit();
+                let val = (self.read(addr) >> bit) & 1 == 1;
+                self.psw.set(PSW_C, val);
+                4
+            }
+            0xCA => {
+                let (addr, bit) = self.mem_bit();
+                let val = self.read(addr) & !(1 << bit);
+                let carry = self.psw.contains(PSW_C) as u8;
+                self.write(addr, val | (carry << bit));
+                6
+            }
+            0xEA => {
+                let (addr, bit) = self.mem_bit();
+                let val = self.read(addr);
+                self.write(addr, val ^ (1 << bit));
+                5
+            }
+
+            // 16 bit ops on YA and the direct page
+            0x1A | 0x3A => {
+                let addr = self.fetch();
+                let val = self.read_dp_u16(addr);
+                let val = if op == 0x1A { val.wrapping_sub(1) } else { val.wrapping_add(1) };
+                self.write_dp_u16(addr, val);
+                self.set_nz16(val);
+                6
+            }
+            0x5A => {
+                let addr = self.fetch();
+                let val = self.read_dp_u16(addr);
+                let ya = self.ya();
+                self.psw.set(PSW_C, ya >= val);
+                self.set_nz16(ya.wrapping_sub(val));
+                4
+            }
+            0x7A => {
+                let addr = self.fetch();
+                let val = self.read_dp_u16(addr);
+                let ya = self.ya();
+                let result = ya as u32 + val as u32;
+                let sum = result as u16;
+                self.psw.set(PSW_V, !(ya ^ val) & (ya ^ sum) & 0x8000 == 0x8000);
+                self.psw.set(PSW_H, (ya & 0xFFF) + (val & 0xFFF) > 0xFFF);
+                self.psw.set(PSW_C, result > 0xFFFF);
+                self.set_nz16(sum);
+                self.set_ya(sum);
+                5
+            }
+            0x9A => {
+                let addr = self.fetch();
+                let val = self.read_dp_u16(addr);
+                let ya = self.ya();
+                let diff = ya.wrapping_sub(val);
+                self.psw.set(PSW_V, (ya ^ val) & (ya ^ diff) & 0x8000 == 0x8000);
+                self.psw.set(PSW_H, (ya & 0xFFF) >= (val & 0xFFF));
+                self.psw.set(PSW_C, ya >= val);
+                self.set_nz16(diff);
+                self.set_ya(diff);
+                5
+            }
+            0xBA => {
+                let addr = self.fetch();
+                let val = self.read_dp_u16(addr);
+                self.set_ya(val);
+                self.set_nz16(val);
+                5
+            }
+            0xDA => {
+                let addr = self.fetch();
+                let ya = self.ya();
+                self.write_dp_u16(addr, ya);
+                5
+            }
+            0xFA => {
+                let src = self.fetch();
+                let val = self.read_dp(src);
+                let dest = self.fetch();
+                self.write_dp(dest, val);
+                5
+            }
+
+            // Compares and moves on X and Y
+            0xC8 => { let val = self.fetch(); let x = self.x; self.compare(x, val); 2 }
+            0xAD => { let val = self.fetch(); let y = self.y; self.compare(y, val); 2 }
+            0x3E => { let addr = self.fetch(); let val = self.read_dp(addr); let x = self.x; self.compare(x, val); 3 }
+            0x1E => { let addr = self.fetch_u16(); let val = self.read(addr); let x = self.x; self.compare(x, val); 4 }
+            0x7E => { let addr = self.fetch(); let val = self.read_dp(addr); let y = self.y; self.compare(y, val); 3 }
+            0x5E => { let addr = self.fetch_u16(); let val = self.read(addr); let y = self.y; self.compare(y, val); 4 }
+
+            0xD8 => { let addr = self.fetch(); let x = self.x; self.write_dp(addr, x); 4 }
+            0xD9 => { let addr = self.fetch().wrapping_add(self.y); let x = self.x; self.write_dp(addr, x); 5 }
+            0xC9 => { let addr = self.fetch_u16(); let x = self.x; self.write(addr, x); 5 }
+            0xCB => { let addr = self.fetch(); let y = self.y; self.write_dp(addr, y); 4 }
+            0xDB => { let addr = self.fetch().wrapping_add(self.x); let y = self.y; self.write_dp(addr, y); 5 }
+            0xCC => { let addr = self.fetch_u16(); let y = self.y; self.write(addr, y); 5 }
+
+            0xCD => { let val = self.fetch(); self.x = val; self.set_nz(val); 2 }
+            0xF8 => { let addr = self.fetch(); let val = self.read_dp(addr); self.x = val; self.set_nz(val); 3 }
+            0xF9 => { let addr = self.fetch().wrapping_add(self.y); let val = self.read_dp(addr); self.x = val; self.set_nz(val); 4 }
+            0xE9 => { let addr = self.fetch_u16(); let val = self.read(addr); self.x = val; self.set_nz(val); 4 }
+            0x8D => { let val = self.fetch(); self.y = val; self.set_nz(val); 2 }
+            0xEB => { let addr = self.fetch(); let val = self.read_dp(addr); self.y = val; self.set_nz(val); 3 }
+            0xFB => { let addr = self.fetch().wrapping_add(self.x); let val = self.read_dp(addr); self.y = val; self.set_nz(val); 4 }
+            0xEC => { let addr = self.fetch_u16(); let val = self.read(addr); self.y = val; self.set_nz(val); 4 }
+            0xE8 => { let val = self.fetch(); self.a = val; self.set_nz(val); 2 }
+            0x8F => {
+                let val = self.fetch();
+                let addr = self.fetch();
+                self.write_dp(addr, val);
+                5
+            }
+
+            // Register transfers
+            0x5D => { let val = self.a; self.x = val; self.set_nz(val); 2 }
+            0x7D => { let val = self.x; self.a = val; self.set_nz(val); 2 }
+            0xDD => { let val = self.y; self.a = val; self.set_nz(val); 2 }
+            0xFD => { let val = self.a; self.y = val; self.set_nz(val); 2 }
+            0x9D => { let val = self.sp; self.x = val; self.set_nz(val); 2 }
+            0xBD => { self.sp = self.x; 2 }
+
+            // Register increments and decrements
+            0x1D => { let val = self.x.wrapping_sub(1); self.x = val; self.set_nz(val); 2 }
+            0x3D => { let val = self.x.wrapping_add(1); self.x = val; self.set_nz(val); 2 }
+            0xDC => { let val = self.y.wrapping_sub(1); self.y = val; self.set_nz(val); 2 }
+            0xFC => { let val = self.y.wrapping_add(1); self.y = val; self.set_nz(val); 2 }
+
+            // MOV (X)+ auto-increments X
+            0xAF => {
+                let addr = self.dp(self.x);
+                let a = self.a;
+                self.write(addr, a);
+                self.x = self.x.wrapping_add(1);
+                4
+            }
+            0xBF => {
+                let addr = self.dp(self.x);
+                let val = self.read(addr);
+                self.a = val;
+                self.set_nz(val);
+                self.x = self.x.wrapping_add(1);
+                4
+            }
+
+            // Stack
+            0x0D => { let psw = self.psw.bits; self.push(psw); 4 }
+            0x2D => { let a = self.a; self.push(a); 4 }
+            0x4D => { let x = self.x; self.push(x); 4 }
+            0x6D => { let y = self.y; self.push(y); 4 }
+            0x8E => { self.psw = Psw::from_bits_truncate(self.pop()); 4 }
+            0xAE => { self.a = self.pop(); 4 }
+            0xCE => { self.x = self.pop(); 4 }
+            0xEE => { self.y = self.pop(); 4 }
+
+            // Test and set/clear bits against A
+            0x0E | 0x4E => {
+                let addr = self.fetch_u16();
+                let val = self.read(addr);
+                let a = self.a;
+                self.set_nz(a.wrapping_sub(val));
+                match op {
+                    0x0E => self.write(addr, val | a),
+                    _ => self.write(addr, val & !a),
+                }
+                6
+            }
+
+            // Compare or decrement and branch
+            0x2E => {
+                let addr = self.fetch();
+                let val = self.read_dp(addr);
+                let a = self.a;
+                self.branch(a != val, 5)
+            }
+            0xDE => {
+                let addr = self.fetch().wrapping_add(self.x);
+                let val = self.read_dp(addr);
+                let a = self.a;
+                self.branch(a != val, 6)
+            }
+            0x6E => {
+                let addr = self.fetch();
+                let val = self.read_dp(addr).wrapping_sub(1);
+                self.write_dp(addr, val);
+                self.branch(val != 0, 5)
+            }
+            0xFE => {
+                self.y = self.y.wrapping_sub(1);
+                let y = self.y;
+                self.branch(y != 0, 4)
+            }
+
+            // Jumps and calls
+            0x5F => { self.pc = self.fetch_u16(); 3 }
+            0x1F => {
+                let addr = self.fetch_u16().wrapping_add(self.x as u16);
+                self.pc = self.read_u16(addr);
+                6
+            }
+            0x3F => {
+                let addr = self.fetch_u16();
+                self.push_pc();
+                self.pc = addr;
+                8
+            }
+            0x4F => {
+                let addr = self.fetch();
+                self.push_pc();
+                self.pc = 0xFF00 | addr as u16;
+                6
+            }
+            0x0F => {
+                self.push_pc();
+                let psw = self.psw.bits;
+                self.push(psw);
+                self.psw.insert(PSW_B);
+                self.psw.remove(PSW_I);
+                self.pc = self.read_u16(0xFFDE);
+                8
+            }
+            0x6F => { self.pop_pc(); 5 }
+            0x7F => {
+                self.psw = Psw::from_bits_truncate(self.pop());
+                self.pop_pc();
+                6
+            }
+
+            // Arithmetic on A and YA
+            0x9F => {
+                let val = (self.a >> 4) | (self.a << 4);
+                self.a = val;
+                self.set_nz(val);
+                5
+            }
+            0xCF => {
+                let ya = self.y as u16 * self.a as u16;
+                self.set_ya(ya);
+                let y = self.y;
+                self.set_nz(y);
+                9
+            }
+            0x9E => {
+                // Quotients over 511 come out the way the chip
+                // Computes them rather than right
+                let ya = self.ya() as u32;
+                let x = self.x as u32;
+
+                self.psw.set(PSW_V, self.y as u32 >= x);
+                self.psw.set(PSW_H, (self.y & 0xF) >= (self.x & 0xF));
+
+                if (self.y as u32) < (x << 1) {
+                    self.a = (ya / x) as u8;
+                    self.y = (ya % x) as u8;
+                } else {
+                    self.a = (255 - (ya - (x << 9)) / (256 - x)) as u8;
+                    self.y = (x + (ya - (x << 9)) % (256 - x)) as u8;
+                }
+
+                let a = self.a;
+                self.set_nz(a);
+                12
+            }
+            0xDF => {
+                if self.psw.contains(PSW_C) || self.a > 0x99 {
+                    self.a = self.a.wrapping_add(0x60);
+                    self.psw.insert(PSW_C);
+                }
+                if self.psw.contains(PSW_H) || (self.a & 0xF) > 9 {
+                    self.a = self.a.wrapping_add(0x06);
+                }
+                let a = self.a;
+                self.set_nz(a);
+                3
+            }
+            0xBE => {
+                if !self.psw.contains(PSW_C) || self.a > 0x99 {
+                    self.a = self.a.wrapping_sub(0x60);
+                    self.psw.remove(PSW_C);
+                }
+                if !self.psw.contains(PSW_H) || (self.a & 0xF) > 9 {
+                    self.a = self.a.wrapping_sub(0x06);
+                }
+                let a = self.a;
+                self.set_nz(a);
+                3
+            }
+
+            // SLEEP and STOP
+            0xEF | 0xFF => {
+                self.stopped = true;
+                3
+            }
+
+            _ => unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a program from $0200 for as many instructions as given
+    fn run(program: &[u8], steps: usize) -> Apu {
+        let mut apu = Apu::new();
+        apu.aram[0x0200..0x0200 + program.len()].copy_from_slice(program);
+        apu.pc = 0x0200;
+        for _ in 0..steps {
+            apu.step();
+        }
+        apu
+    }
+
+    fn divide(ya: u16, x: u8) -> Apu {
+        let mut apu = run(&[], 0);
+        apu.set_ya(ya);
+        apu.x = x;
+        apu.aram[0x0200] = 0x9E;
+        apu.step();
+        apu
+    }
+
+    #[test]
+    fn div_remainder() {
+        let apu = divide(0x0100, 0x03);
+        assert_eq!((apu.a, apu.y), (0x55, 0x01));
+        assert!(!apu.psw.contains(PSW_V));
+    }
+
+    #[test]
+    fn div_overflow() {
+        // A quotient over 255 keeps its low byte
+        let apu = divide(0x1234, 0x10);
+        assert_eq!((apu.a, apu.y), (0x23, 0x04));
+        assert!(apu.psw.contains(PSW_V | PSW_H));
+
+        // Past 511 and by 0 it's the chip's own arithmetic
+        let apu = divide(0x1234, 0x00);
+        assert_eq!((apu.a, apu.y), (0xED, 0x34));
+        assert!(apu.psw.contains(PSW_V | PSW_N));
+    }
+
+    #[test]
+    fn daa() {
+        // MOV A,#$45 CLRC ADC A,#$55 DAA
+        let apu = run(&[0xE8, 0x45, 0x60, 0x88, 0x55, 0xDF], 4);
+        assert_eq!(apu.a, 0x00);
+        assert!(apu.psw.contains(PSW_C | PSW_Z));
+
+        // MOV A,#$19 CLRC ADC A,#$28 DAA
+        let apu = run(&[0xE8, 0x19, 0x60, 0x88, 0x28, 0xDF], 4);
+        assert_eq!(apu.a, 0x47);
+        assert!(!apu.psw.contains(PSW_C));
+    }
+
+    #[test]
+    fn das() {
+        // MOV A,#$10 SETC SBC A,#$01 DAS
+        let apu = run(&[0xE8, 0x10, 0x80, 0xA8, 0x01, 0xBE], 4);
+        assert_eq!(apu.a, 0x09);
+        assert!(apu.psw.contains(PSW_C));
+
+        // MOV A,#$10 SETC SBC A,#$20 DAS
+        let apu = run(&[0xE8, 0x10, 0x80, 0xA8, 0x20, 0xBE], 4);
+        assert_eq!(apu.a, 0x90);
+        assert!(!apu.psw.contains(PSW_C));
+    }
+
+    fn word(op: u8, ya: u16, val: u16) -> Apu {
+        let mut apu = run(&[], 0);
+        apu.set_ya(ya);
+        apu.aram[0x0010] = val as u8;
+        apu.aram[0x0011] = (val >> 8) as u8;
+        apu.aram[0x0200] = op;
+        apu.aram[0x0201] = 0x10;
+        apu.step();
+        apu
+    }
+
+    // H is the carry out of bit 11, and for SUBW no borrow into it
+    #[test]
+    fn addw_half_carry() {
+        let apu = word(0x7A, 0x0FFF, 0x0001);
+        assert_eq!(apu.ya(), 0x1000);
+        assert!(apu.psw.contains(PSW_H));
+        assert!(!apu.psw.contains(PSW_C));
+
+        let apu = word(0x7A, 0x0100, 0x0100);
+        assert_eq!(apu.ya(), 0x0200);
+        assert!(!apu.psw.contains(PSW_H));
+    }
+
+    #[test]
+    fn subw_half_carry() {
+        let apu = word(0x9A, 0x1000, 0x0001);
+        assert_eq!(apu.ya(), 0x0FFF);
+        assert!(!apu.psw.contains(PSW_H));
+        assert!(apu.psw.contains(PSW_C));
+
+        let apu = word(0x9A, 0x1001, 0x0001);
+        assert_eq!(apu.ya(), 0x1000);
+        assert!(apu.psw.contains(PSW_H | PSW_C));
+    }
+
+    // Timer 2 ticks every 16 cycles
+    fn ticks(apu: &mut Apu, n: usize) {
+        for _ in 0..n {
+            apu.tick(TIMER_CYCLES as u8);
+        }
+    }
+
+    #[test]
+    fn timer_target_zero() {
+        let mut apu = run(&[], 0);
+        apu.write(0x00FC, 0x00);
+        apu.write(0x00F1, 0x04);
+
+        ticks(&mut apu, 255);
+        assert_eq!(apu.timers[2].out, 0);
+        ticks(&mut apu, 1);
+        assert_eq!(apu.timers[2].out, 1);
+    }
+
+    #[test]
+    fn timer_read_clears() {
+        let mut apu = run(&[], 0);
+        apu.write(0x00FC, 0x02);
+        apu.write(0x00F1, 0x04);
+
+        ticks(&mut apu, 7);
+        assert_eq!(apu.read(0x00FF), 3);
+        assert_eq!(apu.read(0x00FF), 0);
+        ticks(&mut apu, 1);
+        assert_eq!(apu.read(0x00FF), 1);
+    }
+}
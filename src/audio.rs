@@ -0,0 +1,257 @@
+use std::fs::File;
+use std::io::{self, Write, Seek, SeekFrom, BufWriter};
+use std::f64::consts::PI;
+use std::cmp;
+use std::sync::{Arc, Mutex};
+
+// The S-DSP puts out one stereo sample every 32 APU cycles
+pub const DSP_RATE: u32 = 32000;
+
+// Half the taps of the resampling filter, and how many
+// Fractional positions between two samples get their own
+const HALF_TAPS: usize = 16;
+const TAPS: usize = HALF_TAPS * 2;
+const PHASES: usize = 256;
+
+// Anything that takes the sound of the SNES, samples are
+// Interleaved left and right
+pub trait AudioSink {
+    fn write(&mut self, samples: &[i16]);
+
+    // No more samples are coming, flush anything buffered
+    fn finish(&mut self) { }
+}
+
+// Where the SNES sends its sound, shared with whoever has
+// To finish it, nothing hears it when unset
+pub type SharedSink = Arc<Mutex<Option<Box<AudioSink + Send>>>>;
+
+pub fn shared_sink() -> SharedSink {
+    Arc::new(Mutex::new(None))
+}
+
+// Throws the sound away
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[i16]) { }
+}
+
+// Windowed sinc resampler in front of another sink
+pub struct Resampler {
+    sink: Box<AudioSink + Send>,
+    step: f64,
+    pos: f64,
+    table: Vec<f32>,
+    history: Vec<[f32; 2]>,
+    out: Vec<i16>,
+}
+
+impl Resampler {
+    pub fn new(rate: u32, sink: Box<AudioSink + Send>) -> Resampler {
+        // Stay a little under the lower of the two Nyquist limits
+        let cutoff = 0.9 * (rate as f64 / DSP_RATE as f64).min(1.0);
+
+        let mut table = vec![0f32; PHASES * TAPS];
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0f64;
+            let mut row = [0f64; TAPS];
+            for tap in 0..TAPS {
+                let d = (tap as f64 - HALF_TAPS as f64 + 1.0) - frac;
+                let x = PI * cutoff * d;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                // Blackman window across the whole filter
+                let w = d / HALF_TAPS as f64;
+                let window = if w.abs() >= 1.0 {
+                    0.0
+                } else {
+                    0.42 + 0.5 * (PI * w).cos() + 0.08 * (2.0 * PI * w).cos()
+                };
+                row[tap] = sinc * window;
+                sum += row[tap];
+            }
+            // Unity gain at every phase
+            for tap in 0..TAPS {
+                table[phase * TAPS + tap] = (row[tap] / sum) as f32;
+            }
+        }
+
+        Resampler {
+            sink: sink,
+            step: DSP_RATE as f64 / rate as f64,
+            pos: (HALF_TAPS - 1) as f64,
+            table: table,
+            history: vec![[0f32; 2]; HALF_TAPS - 1],
+            out: Vec::new(),
+        }
+    }
+
+    fn resample(&mut self) {
+        self.out.clear();
+
+        while (self.pos as usize) + HALF_TAPS < self.history.len() {
+            let index = self.pos as usize;
+            let phase = ((self.pos - index as f64) * PHASES as f64) as usize;
+            let phase = cmp::min(phase, PHASES - 1);
+            let coeffs = &self.table[phase * TAPS..(phase + 1) * TAPS];
+            let start = index + 1 - HALF_TAPS;
+
+            let mut l = 0f32;
+            let mut r = 0f32;
+            for tap in 0..TAPS {
+                let sample = self.history[start + tap];
+                l += sample[0] * coeffs[tap];
+                r += sample[1] * coeffs[tap];
+            }
+            self.out.push(to_i16(l));
+            self.out.push(to_i16(r));
+
+            self.pos += self.step;
+        }
+
+        // Keep only what the next output still needs
+        let used = (self.pos as usize).saturating_sub(HALF_TAPS - 1);
+        if used > 0 {
+            self.history.drain(..used);
+            self.pos -= used as f64;
+        }
+
+        self.sink.write(&self.out);
+    }
+}
+
+impl AudioSink for Resampler {
+    fn write(&mut self, samples: &[i16]) {
+        for frame in samples.chunks(2) {
+            if frame.len() == 2 {
+                self.history.push([frame[0] as f32, frame[1] as f32]);
+            }
+        }
+        self.resample();
+    }
+
+    fn finish(&mut self) {
+        // Push the tail of the sound through the filter
+        for _ in 0..HALF_TAPS {
+            self.history.push([0f32; 2]);
+        }
+        self.resample();
+        self.sink.finish();
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    let sample = sample.round();
+    if sample > 32767.0 {
+        32767
+    } else if sample < -32768.0 {
+        -32768
+    } else {
+        sample as i16
+    }
+}
+
+// 16 bit stereo PCM .wav file, the sizes in the header
+// Get filled in once the sink is finished
+pub struct WavSink {
+    file: BufWriter<File>,
+    rate: u32,
+    bytes: u32,
+    done: bool,
+}
+
+impl WavSink {
+    pub fn create(path: &str, rate: u32) -> io::Result<WavSink> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write_header(&mut file, rate, 0)?;
+
+        Ok(WavSink {
+            file: file,
+            rate: rate,
+            bytes: 0,
+            done: false,
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[i16]) {
+        if self.done {
+            return;
+        }
+
+        let mut buf = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            buf.push(sample as u8);
+            buf.push((sample >> 8) as u8);
+        }
+
+        match self.file.write_all(&buf) {
+            Ok(_) => self.bytes = self.bytes.wrapping_add(buf.len() as u32),
+            Err(err) => {
+                println!("Could not write audio: {}", err);
+                self.done = true;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+
+        let rate = self.rate;
+        let bytes = self.bytes;
+        let result = self.file.seek(SeekFrom::Start(0))
+            .and_then(|_| write_header(&mut self.file, rate, bytes))
+            .and_then(|_| self.file.flush());
+        if let Err(err) = result {
+            println!("Could not finish audio: {}", err);
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+pub fn write_u16<W: Write>(w: &mut W, value: u16) -> io::Result<()> {
+    w.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+pub fn write_u32<W: Write>(w: &mut W, value: u32) -> io::Result<()> {
+    w.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+pub fn write_header<W: Write>(w: &mut W, rate: u32, bytes: u32) -> io::Result<()> {
+    w.write_all(b"RIFF")?;
+    write_u32(w, bytes.wrapping_add(36))?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    write_u32(w, 16)?;
+    // PCM, 2 channels, 16 bits
+    write_u16(w, 1)?;
+    write_u16(w, 2)?;
+    write_u32(w, rate)?;
+    write_u32(w, rate * 4)?;
+    write_u16(w, 4)?;
+    write_u16(w, 16)?;
+
+    w.write_all(b"data")?;
+    write_u32(w, bytes)
+}
+
+// Builds the sink chain for a host rate, the DSP rate needs
+// No resampling
+pub fn sink(rate: u32, sink: Box<AudioSink + Send>) -> Box<AudioSink + Send> {
+    if rate == DSP_RATE {
+        sink
+    } else {
+        Box::new(Resampler::new(rate, sink))
+    }
+}
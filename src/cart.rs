@@ -49,6 +49,33 @@ impl From<Vec<u8>> for SnesHeader {
     }
 }
 
+impl SnesHeader {
+    // The first byte of the license is the destination
+    // Country, Europe, China, Indonesia and Australia are PAL
+    pub fn pal(&self) -> bool {
+        match self.license_id[0] {
+            0x02...0x0C | 0x11 => true,
+            _ => false,
+        }
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom_size
+    }
+
+    pub fn rom_type(&self) -> u8 {
+        self.rom_type
+    }
+
+    // Chipsets $13-$15 and $1A carry a Super FX
+    pub fn superfx(&self) -> bool {
+        match self.rom_type {
+            0x13...0x15 | 0x1A => true,
+            _ => false,
+        }
+    }
+}
+
 impl Index<usize> for SnesCart {
     type Output = u8;
 
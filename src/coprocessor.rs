@@ -0,0 +1,34 @@
+// A chip on the cartridge running next to the CPU. The
+// Scheduler catches it up after every step of the CPU.
+pub trait Coprocessor {
+    fn name(&self) -> &'static str;
+
+    fn reset(&mut self);
+
+    // Run for `clocks` master clocks
+    fn run(&mut self, clocks: u32);
+
+    // Its registers and memory as the CPU sees them, None leaves
+    // The address to the rest of the bus
+    fn read(&mut self, _addr: u16, _bank: u8, _open_bus: u8) -> Option<u8> {
+        None
+    }
+
+    // False leaves the write to the rest of the bus
+    fn write(&mut self, _addr: u16, _bank: u8, _val: u8) -> bool {
+        false
+    }
+
+    // The line into the IRQ pin of the CPU
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn box_clone(&self) -> Box<Coprocessor + Send>;
+}
+
+impl Clone for Box<Coprocessor + Send> {
+    fn clone(&self) -> Box<Coprocessor + Send> {
+        self.box_clone()
+    }
+}
@@ -1,5 +1,4 @@
 use inst::{Instruction, Opcode, Value};
-use scrn::{Screen, Scrn};
 use cart::SnesCart;
 use snes::SNES;
 use regs::*;
@@ -21,15 +20,18 @@ bitflags! {
     }
 }
 
+// Reported in the low bits of RDNMI
+const CPU_VERSION: u8 = 2;
+
 #[derive(Debug, Clone, Default)]
 pub struct Ricoh5A22 {
     pub pc: u16,
     pub p_reg: PReg,
     nmitimen: u8,
+    wrio: u8,
     emulation: bool,
     hdmaen: u8,
     mdmaen: u8,
-    inidisp: u8,
     fastrom: bool,
     a_reg: u16,
     y_reg: u16,
@@ -38,19 +40,40 @@ pub struct Ricoh5A22 {
     stack_ptr: u16,
     pbr: u8,
     dbr: u8,
-    DMA7: [u8; 7],
-    DMA_addr: [u16; 7],
-    DMA_size: [u16; 7],
-    DMA_bank: [u8; 7],
-    DMA_dest: [u8; 7],
-    DMAP: [DMAControl; 7],
-    VMAIN: VMAIN,
-    BGMODE: BGMODE,
-    BGXSC: [BGXSC; 4],
-    BG12NBA: BGNBA,
-    BG34NBA: BGNBA,
-    TM: SCRDES,
-    TS: SCRDES,
+    DMA_addr: [u16; 8],
+    DMA_size: [u16; 8],
+    DMA_bank: [u8; 8],
+    DMA_dest: [u8; 8],
+    DMAP: [u8; 8],
+    DMA_unused: [u8; 8],
+    HDMA_bank: [u8; 8],
+    HDMA_addr: [u16; 8],
+    HDMA_line: [u8; 8],
+    hdma_transfer: [bool; 8],
+    hdma_terminated: [bool; 8],
+    // Master clocks DMA and HDMA have held the CPU for
+    stall: u32,
+    // Bytes the running DMA channel has moved so far
+    dma_index: u32,
+    // Master clocks the bus accesses of the running instruction
+    // Took, and how many accesses there were
+    bus_clocks: Cell<u32>,
+    bus_accesses: Cell<u32>,
+    nmi_flag: Cell<bool>,
+    nmi_pending: bool,
+    htime: u16,
+    vtime: u16,
+    timeup: Cell<bool>,
+    wrmpya: u8,
+    wrmpyb: u8,
+    wrdiva: u16,
+    wrdivb: u8,
+    rddiv: u16,
+    rdmpy: u16,
+    mul_cycles: u8,
+    div_cycles: u8,
+    alu_shift: u32,
+    joy: [u16; 4],
 }
 
 impl Ricoh5A22 {
@@ -65,15 +88,15 @@ impl Ricoh5A22 {
         // Set emulation mode
         self.emulation = true;
 
+        // All I/O pins float high
+        self.wrio = 0xFFu8;
+
         // Get rid of any HDMA
         self.hdmaen = 0u8;
 
         // Get rid of any MDMA
         self.mdmaen = 0u8;
 
-        // Set the display off with 0 brightness
-        self.inidisp = 0u8;
-
         // We're in slow ROM territory
         self.fastrom = false;
 
@@ -96,12 +119,21 @@ impl Ricoh5A22 {
         // Set the Data Bank Register
         self.dbr = 0u8;
 
-        self.VMAIN = Default::default();
-
         println!("CPU Reset, PC: ${:X}", self.pc);
     }
 
     pub fn step(&mut self, mem: &mut Memory) -> Result<u8, String> {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.nmi(mem);
+        }
+
+        // The IRQ line stays up until TIMEUP is read, or
+        // Until the cartridge lets go of it
+        if (self.timeup.get() || mem.coprocessor_irq()) && !self.p_reg.contains(FLAG_I) {
+            self.irq(mem);
+        }
+
         print!("0x{:4X}: ", self.pc);
         match Instruction::from(self, mem) {
             Instruction(Opcode::SEI, _) => {
@@ -113,16 +145,18 @@ impl Ricoh5A22 {
             Instruction(Opcode::STZ, Value::Absolute(addr)) => {
                 println!("STZ ${:X}", addr);
 
+                let dbr = self.dbr;
+
                 // Set zero at location
                 match self.p_reg.contains(FLAG_M) {
                     // If 16 bit accumulator write two bytes
                     false => {
-                        self.write_u16(mem, addr, 0u16);
+                        self.write_u16(mem, addr, dbr, 0u16);
                         Ok(5)
                     }
                     // If 8 bit accumulator write one byte
                     true => {
-                        self.write_u8(mem, addr, 0u8);
+                        self.write_u8(mem, addr, dbr, 0u8);
                         Ok(4)
                     }
                 }
@@ -220,12 +254,13 @@ impl Ricoh5A22 {
                 println!("STA ${:X}", addr);
 
                 let a = self.a_reg;
+                let dbr = self.dbr;
                 
                 // Store A at address
                 match self.p_reg.contains(FLAG_M) {
                     // If 16 bit accumulator write the C register
                     false => {
-                        self.write_u16(mem, addr, a);
+                        self.write_u16(mem, addr, dbr, a);
 
                         // Set the Zero flag
                         if a == 0 {
@@ -244,7 +279,7 @@ impl Ricoh5A22 {
                     }
                     // If 8 bit accumulator write the A register
                     true => {
-                        self.write_u8(mem, addr, (a & 0xFF) as u8);
+                        self.write_u8(mem, addr, dbr, (a & 0xFF) as u8);
 
                         // Set the Zero flag
                         if (a & 0xFF) == 0 {
@@ -329,7 +364,7 @@ impl Ricoh5A22 {
                     // If accumulator is 16 bit write C at
                     // Direct Page + offset
                     false => {
-                        self.write_u16(mem,dp + offset as u16, a);
+                        self.write_u16(mem, dp + offset as u16, 0, a);
                         // Add one more cycle because we have
                         // Written one more byte
                         cycles += 1;
@@ -337,7 +372,7 @@ impl Ricoh5A22 {
                     // If accumulator is 8 bit write A at
                     // Direct Page + offset
                     true => {
-                        self.write_u8(mem, dp + offset as u16, (a & 0xFF) as u8);
+                        self.write_u8(mem, dp + offset as u16, 0, (a & 0xFF) as u8);
                     }
                 }
 
@@ -612,12 +647,13 @@ impl Ricoh5A22 {
 
                 // We got Rust again!
                 let x = self.x_reg;
+                let dbr = self.dbr;
                 
                 // Store X at address
                 match self.p_reg.contains(FLAG_X) {
                     // If Index registers are 8 bit
                     true => {
-                        self.write_u8(mem, addr, (x & 0xFF) as u8);
+                        self.write_u8(mem, addr, dbr, (x & 0xFF) as u8);
 
                         // Set the Zero flag
                         if (x & 0xFF) == 0 {
@@ -637,7 +673,7 @@ impl Ricoh5A22 {
                     }
                     // If Index registers are 16 bit
                     false => {
-                        self.write_u16(mem, addr, x);
+                        self.write_u16(mem, addr, dbr, x);
 
                         // Set the Zero flag
                         if self.x_reg == 0 {
@@ -672,12 +708,12 @@ impl Ricoh5A22 {
                     // If memory is 16 bit write Zero at
                     // Direct Page + offset + x
                     false => {
-                        self.write_u16(mem,dp + x + offset as u16, 0u16);
+                        self.write_u16(mem, dp + x + offset as u16, 0, 0u16);
                     }
                     // If accumulator is 8 bit write A at
                     // Direct Page + offset
                     true => {
-                        self.write_u8(mem, dp + x + offset as u16, 0u8);
+                        self.write_u8(mem, dp + x + offset as u16, 0, 0u8);
                     }
                 }
 
@@ -1034,7 +1070,7 @@ impl Ricoh5A22 {
                     // 8 bit Index registers
                     // Direct Page + offset
                     false => {
-                        self.write_u16(mem,dp + offset as u16, x);
+                        self.write_u16(mem, dp + offset as u16, 0, x);
 
                         // Set the Zero flag
                         if x == 0 {
@@ -1057,7 +1093,7 @@ impl Ricoh5A22 {
                     // 16 bit Index registers
                     // Direct Page + offset
                     true => {
-                        self.write_u8(mem, dp + offset as u16, (x & 0xFF) as u8);
+                        self.write_u8(mem, dp + offset as u16, 0, (x & 0xFF) as u8);
 
                         // Set the Zero flag
                         if (x & 0xFF) == 0 {
@@ -1318,12 +1354,13 @@ impl Ricoh5A22 {
 
                 // We got Rust again!
                 let y = self.y_reg;
+                let dbr = self.dbr;
                 
                 // Store Y at address
                 match self.p_reg.contains(FLAG_X) {
                     // If Index registers are 8 bit
                     true => {
-                        self.write_u8(mem, addr, (y & 0xFF) as u8);
+                        self.write_u8(mem, addr, dbr, (y & 0xFF) as u8);
 
                         // Set the Zero flag
                         if (y & 0xFF) == 0 {
@@ -1343,7 +1380,7 @@ impl Ricoh5A22 {
                     }
                     // If Index registers are 16 bit
                     false => {
-                        self.write_u16(mem, addr, y);
+                        self.write_u16(mem, addr, dbr, y);
 
                         // Set the Zero flag
                         if self.y_reg == 0 {
@@ -1377,20 +1414,76 @@ impl Ricoh5A22 {
     }
 
     pub fn read_u8(&self, mem: &Memory, addr: u16, bank: u8) -> u8 {
-        match addr {
+        self.access(addr, bank);
+        self.bus_read(mem, addr, bank)
+    }
+
+    // A read nobody pays for, DMA keeps its own time
+    fn bus_read(&self, mem: &Memory, addr: u16, bank: u8) -> u8 {
+        let val = match addr {
+            _ if bank & 0x40 == 0x40 => mem.peek_u8(addr, bank),
             0x2000 => 0u8,
-            0x4210 => 0x42u8,
-            0x2140...0x2143 => 0u8,
-            0x4300...0x4306 => 0u8,
-            0x4310...0x4316 => 0u8,
-            0x4320...0x4326 => 0u8,
-            0x4330...0x4336 => 0u8,
-            0x4340...0x4346 => 0u8,
-            0x4350...0x4356 => 0u8,
-            0x4360...0x4366 => 0u8,
-            0x4370...0x4376 => self.DMA7[(addr - 0x4370) as usize],
+            0x4210 => {
+                // Reading RDNMI acknowledges the NMI
+                let flag = self.nmi_flag.get();
+                self.nmi_flag.set(false);
+                (if flag { 0x80 } else { 0x00 }) | (mem.open_bus.get() & 0x70) | CPU_VERSION
+            }
+            0x4211 => {
+                // Reading TIMEUP acknowledges the IRQ
+                let flag = self.timeup.get();
+                self.timeup.set(false);
+                (if flag { 0x80 } else { 0x00 }) | (mem.open_bus.get() & 0x7F)
+            }
+            // Manual reads clock the controllers one bit at a time
+            0x4016 => (mem.open_bus.get() & 0xFC) | (mem.port1.borrow_mut().clock() & 3),
+            0x4017 => (mem.open_bus.get() & 0xE0) | 0x1C | (mem.port2.borrow_mut().clock() & 3),
+            0x4218...0x421F => {
+                let joy = self.joy[((addr - 0x4218) >> 1) as usize];
+                match addr & 1 {
+                    0 => (joy & 0xFF) as u8,
+                    _ => (joy >> 8) as u8,
+                }
+            }
+            // Nothing drives the pins yet, they read back WRIO
+            0x4213 => self.wrio,
+            0x4214 => (self.rddiv & 0xFF) as u8,
+            0x4215 => (self.rddiv >> 8) as u8,
+            0x4216 => (self.rdmpy & 0xFF) as u8,
+            0x4217 => (self.rdmpy >> 8) as u8,
+            0x4212 => {
+                let timing = &mem.ppu.timing;
+                (if timing.vblank() { 0x80 } else { 0x00 }) |
+                (if timing.hblank() { 0x40 } else { 0x00 }) |
+                (if timing.auto_joypad_busy() { 0x01 } else { 0x00 }) |
+                (mem.open_bus.get() & 0x3E)
+            }
+            // The APU ports are mirrored up to $217F
+            0x2140...0x217F => mem.apu.cpu_read((addr & 3) as usize),
+            0x4300...0x437F => {
+                let ch = ((addr >> 4) & 7) as usize;
+                match addr & 0xF {
+                    0x0 => self.DMAP[ch],
+                    0x1 => self.DMA_dest[ch],
+                    0x2 => (self.DMA_addr[ch] & 0xFF) as u8,
+                    0x3 => (self.DMA_addr[ch] >> 8) as u8,
+                    0x4 => self.DMA_bank[ch],
+                    0x5 => (self.DMA_size[ch] & 0xFF) as u8,
+                    0x6 => (self.DMA_size[ch] >> 8) as u8,
+                    0x7 => self.HDMA_bank[ch],
+                    0x8 => (self.HDMA_addr[ch] & 0xFF) as u8,
+                    0x9 => (self.HDMA_addr[ch] >> 8) as u8,
+                    0xA => self.HDMA_line[ch],
+                    0xB | 0xF => self.DMA_unused[ch],
+                    _ => mem.open_bus.get(),
+                }
+            }
             _ => mem.peek_u8(addr, bank)
-        }
+        };
+
+        // Whatever was read last stays on the data bus
+        mem.open_bus.set(val);
+        val
     }
 
     pub fn read_u16(&self, mem: &Memory, addr: u16, bank: u8) -> u16 {
@@ -1409,7 +1502,7 @@ impl Ricoh5A22 {
 
     pub fn push_u8(&mut self, mem: &mut Memory, val: u8) {
         let stack_ptr = self.stack_ptr;
-        self.write_u8(mem, stack_ptr, val);
+        self.write_u8(mem, stack_ptr, 0, val);
         self.stack_ptr -= 1;
     }
 
@@ -1434,293 +1527,536 @@ impl Ricoh5A22 {
         high | (low << 8)
     }
 
-    pub fn write_u8(&mut self, mem: &mut Memory, addr: u16, val: u8) {
+    pub fn write_u8(&mut self, mem: &mut Memory, addr: u16, bank: u8, val: u8) {
+        self.access(addr, bank);
+        self.bus_write(mem, addr, bank, val);
+    }
+
+    fn bus_write(&mut self, mem: &mut Memory, addr: u16, bank: u8, val: u8) {
+        // The I/O registers are only in banks $00-$3F and $80-$BF
+        if bank & 0x40 == 0x40 {
+            return mem.write_u8(addr, bank, val);
+        }
+
         match addr {
-            0x2100 => {
-                println!("TODO: INIDISP #${:X}", val);
-                self.inidisp = val;
-            }
-            0x2101...0x2104 => {
-                println!("TODO: REGISTERS 0x2101...0x2120 ({:X})", addr);
-            }
-            0x2105 => {
-                println!("BGMODE: #${:X}", val);
-                self.BGMODE = BGMODE::from(val);
-                println!("{:?}", self.BGMODE);
-            }
-            0x2106 => {
-                println!("TODO: REGISTERS 0x2101...0x2120 ({:X})", addr);
-            }
-            0x2107 => {
-                println!("BG1SC: #${:X}", val);
-                self.BGXSC[0] = BGXSC::from(val);
-                println!("BG1SC: {:?}", self.BGXSC[0]);
-            }
-            0x2108 => {
-                println!("BG2SC: #${:X}", val);
-                self.BGXSC[1] = BGXSC::from(val);
-                println!("BG2SC: {:?}", self.BGXSC[1]);
-            }
-            0x2109 => {
-                println!("BG3SC: #${:X}", val);
-                self.BGXSC[2] = BGXSC::from(val);
-                println!("BG3SC: {:?}", self.BGXSC[2]);
-            }
-            0x210A => {
-                println!("BG4SC: #${:X}", val);
-                self.BGXSC[3] = BGXSC::from(val);
-                println!("BG4SC: {:?}", self.BGXSC[3]);
-            }
-            0x210B => {
-                println!("BG12NBA: #${:X}", val);
-                self.BG12NBA = BGNBA::from(val);
-                println!("BG12NBA: {:?}", self.BG12NBA);
-            }
-            0x210C => {
-                println!("BG34NBA: #${:X}", val);
-                self.BG34NBA = BGNBA::from(val);
-                println!("BG34NBA: {:?}", self.BG34NBA);
-            }
-            0x210D...0x2114 => {
-                println!("TODO: REGISTERS 0x2101...0x2120 ({:X})", addr);
-            }
-            0x2115 => {
-                println!("VMAIN: #${:X}", val);
-                self.VMAIN = VMAIN::from(val);
-                println!("{:?}", self.VMAIN);
-            }
-            0x2116 => {
-                println!("VMADDL: #${:X}", val);
-                unsafe { Scrn::VRAM_ADDR = (Scrn::VRAM_ADDR & 0xFF00) | val as u16; }
-            }
-            0x2117 => {
-                println!("VMADDH: #${:X}", val);
-                unsafe { Scrn::VRAM_ADDR = (Scrn::VRAM_ADDR & 0x00FF) | ((val as u16) << 8); }
-            }
-            0x2116...0x2117 => {
-                println!("TODO: VMADD (${:X}, #${:X})", addr, val);
-            }
-            0x2118 => {
-                match self.VMAIN.remap {
-                    VREMAP::None => {
-                        unsafe {
-                            Scrn::VRAM[Scrn::VRAM_ADDR as usize] = (Scrn::VRAM[Scrn::VRAM_ADDR as usize] & 0xFF00) | val as u16;
-                        
-                            if self.VMAIN.increment == VINC::Byte {
-                                match self.VMAIN.amount {
-                                    VINCAM::One => Scrn::VRAM_ADDR += 1,
-                                    VINCAM::ThirtyTwo => Scrn::VRAM_ADDR += 32,
-                                    VINCAM::OneTwentyEight => Scrn::VRAM_ADDR += 128,
-                                }
-                            }
-                        }
-                    }
-                    _ => panic!("VRAM Remap not supported: {:?}", self.VMAIN),
-                }
+            0x213E => {
+                println!("TODO: STAT77 ${:X}", addr);
             }
-            0x2119 => {
-                match self.VMAIN.remap {
-                    VREMAP::None => {
-                        unsafe {
-                            Scrn::VRAM[Scrn::VRAM_ADDR as usize] = (Scrn::VRAM[Scrn::VRAM_ADDR as usize] & 0x00FF) | ((val as u16) << 8);
-                        
-                            if self.VMAIN.increment == VINC::Word {
-                                match self.VMAIN.amount {
-                                    VINCAM::One => Scrn::VRAM_ADDR += 1,
-                                    VINCAM::ThirtyTwo => Scrn::VRAM_ADDR += 32,
-                                    VINCAM::OneTwentyEight => Scrn::VRAM_ADDR += 128,
-                                }
-                            }
-                        }
-                    }
-                    _ => panic!("VRAM Remap not supported: {:?}", self.VMAIN),
-                }
+            0x2181...0x2183 => {
+                println!("TODO: REGISTERS 0x2181...0x2183 ({:X}", addr);
             }
-            0x2119..0x2121 => {
-                println!("TODO: REGISTERS 0x2101...0x2120 ({:X})", addr);
+            0x2140...0x217F => {
+                println!("APUIO{}: #${:X}", addr & 3, val);
+                mem.apu.cpu_write((addr & 3) as usize, val);
             }
-            0x2121 => {
-                println!("CGADD: {:X}", val);
-                unsafe {
-                    Scrn::PALETTE_INDEX = val as u16 * 2;
-                }
+            0x2180 => {
+                // TODO: WRAM
+            }
+            0x4016 => {
+                println!("JOYWR: #${:X}", val);
+                let latch = val & 1 == 1;
+                mem.port1.borrow_mut().strobe(latch);
+                mem.port2.borrow_mut().strobe(latch);
             }
-            0x2122 => { 
-                println!("CGDATA: {:X}", val);
-                unsafe {
-                    Scrn::PALETTE[Scrn::PALETTE_INDEX as usize] = val;
-                    Scrn::PALETTE_INDEX += 1;
-                    println!("{:X}", Scrn::PALETTE_INDEX);
+            0x4200 => {
+                println!("NMITIMEN: #${:X}", val);
+
+                // Enabling NMI during V-blank fires it right away
+                if self.nmitimen & 0x80 == 0 && val & 0x80 == 0x80 && self.nmi_flag.get() {
+                    self.nmi_pending = true;
                 }
+
+                // Disabling the H/V IRQ drops the line
+                if val & 0x30 == 0 {
+                    self.timeup.set(false);
+                }
+
+                self.nmitimen = val;
             }
-            0x2123...0x212B => {
-                println!("TODO: REGISTERS 0x2123...0x2133 ({:X})", addr);
+            0x4207 => {
+                println!("HTIMEL: #${:X}", val);
+                self.htime = (self.htime & 0x100) | val as u16;
             }
-            0x212C => {
-                println!("TM: #${:X}", val);
-                self.TM = SCRDES::from(val);
-                println!("TM: {:?}", self.TM);
+            0x4208 => {
+                println!("HTIMEH: #${:X}", val);
+                self.htime = (self.htime & 0xFF) | ((val as u16 & 1) << 8);
             }
-            0x212D => {
-                println!("TS: #${:X}", val);
-                self.TS = SCRDES::from(val);
-                println!("TS: {:?}", self.TS);
+            0x4209 => {
+                println!("VTIMEL: #${:X}", val);
+                self.vtime = (self.vtime & 0x100) | val as u16;
             }
-            0x212E...0x2133 => {
-                println!("TODO: REGISTERS 0x2123...0x2133 ({:X})", addr);
+            0x420A => {
+                println!("VTIMEH: #${:X}", val);
+                self.vtime = (self.vtime & 0xFF) | ((val as u16 & 1) << 8);
             }
-            0x213E => {
-                println!("TODO: STAT77 ${:X}", addr);
+            0x4201 => {
+                println!("WRIO: #${:X}", val);
+
+                // Pulling bit 7 low latches the H/V counters
+                if self.wrio & 0x80 == 0x80 && val & 0x80 == 0 {
+                    mem.ppu.latch_counters();
+                }
+
+                mem.ppu.extlatch = val & 0x80 == 0x80;
+
+                // Bits 6 and 7 are the IO lines of the two ports
+                mem.port1.borrow_mut().iobit(val & 0x40 == 0x40);
+                mem.port2.borrow_mut().iobit(val & 0x80 == 0x80);
+                self.wrio = val;
             }
-            0x2181...0x2183 => {
-                println!("TODO: REGISTERS 0x2181...0x2183 ({:X}", addr);
+            0x4202 => {
+                println!("WRMPYA: #${:X}", val);
+                self.wrmpya = val;
             }
-            0x2140...0x2143 => {
-                println!("TODO: APUIO #${:X}", addr);
+            0x4203 => {
+                println!("WRMPYB: #${:X}", val);
+                self.rdmpy = 0;
+
+                // Writes while the unit is busy are ignored
+                if self.mul_cycles == 0 && self.div_cycles == 0 {
+                    self.wrmpyb = val;
+                    self.rddiv = ((val as u16) << 8) | self.wrmpya as u16;
+                    self.alu_shift = val as u32;
+                    self.mul_cycles = 8;
+                }
             }
-            0x2180 => {
-                // TODO: WRAM
+            0x4204 => {
+                println!("WRDIVL: #${:X}", val);
+                self.wrdiva = (self.wrdiva & 0xFF00) | val as u16;
             }
-            0x4200 => {
-                println!("NMITIMEN: #${:X}", val);
-                self.nmitimen = val;
+            0x4205 => {
+                println!("WRDIVH: #${:X}", val);
+                self.wrdiva = (self.wrdiva & 0x00FF) | ((val as u16) << 8);
             }
-            0x4201...0x4203 => {
-                println!("TODO: WRIO/WRMPY(A/B) ${:X}", addr);
+            0x4206 => {
+                println!("WRDIVB: #${:X}", val);
+                self.rdmpy = self.wrdiva;
+
+                if self.mul_cycles == 0 && self.div_cycles == 0 {
+                    self.wrdivb = val;
+                    self.alu_shift = (val as u32) << 16;
+                    self.div_cycles = 16;
+                }
             }
             0x420B => {
                 println!("MDMAEN: #${:X}", val);
-                if val & 0b0000001 == 0b0000001 {
-                    let control = self.DMAP[0];
-
-                    for i in 0...self.DMA_size[0] {
-                        match control.direction {
-                            DMADirection::To => {
-                                match control.mode {
-                                    DMATransferMode::RRW => {
-                                        let desta = 0x2100 | self.DMA_dest[0] as u16;
-                                        let destb = if desta + 1 > 0x21FF { 0x2100 } else { desta + 1 };
-                                        let addr = self.DMA_addr[0];
-                                        let a = self.read_u8(mem, addr + 0, self.DMA_bank[0]);
-                                        let b = self.read_u8(mem, addr + 1, self.DMA_bank[0]);
-                                        self.write_u8(mem, desta, a);
-                                        self.write_u8(mem, destb, b);
-
-                                        match control.transfer {
-                                            DMATransfer::Fixed => { }
-                                            DMATransfer::Adjusted => {
-                                                self.DMA_addr[0] += 2;
-                                            }
-                                        }
-
-                                        if i + 1 >= self.DMA_size[0] / 2 {
-                                            break;
-                                        }
-                                    }
-                                    DMATransferMode::RW => {
-                                        let dest = 0x2100 | self.DMA_dest[0] as u16;
-                                        let a = self.read_u8(mem, self.DMA_addr[0], self.DMA_bank[0]);
-                                        self.write_u8(mem, dest, a);
-
-                                        match control.transfer {
-                                            DMATransfer::Fixed => { }
-                                            DMATransfer::Adjusted => {
-                                                self.DMA_addr[0] += 1;
-                                            }
-                                        }
-                                    }
-                                    _ => panic!("Unsupported DMA mode: {:?}", control)
-                                }
-                            }
-                            _ => panic!("Unsupported DMA mode: {:?}", control)
-                        }
-                    }
+
+                // Lining up with the CPU clock takes 12 to 24
+                // Clocks on top of the channels, take the middle.
+                // The transfer itself is run by the scheduler
+                if val != 0 {
+                    self.stall += 18;
                 }
+                self.mdmaen = val;
+                self.dma_index = 0;
             }
             0x420C => {
                 println!("HDMAEN: #${:X}", val);
-                if val != 0 {
-                    panic!("HDMAEN: #${:X}", val);
-                }
                 self.hdmaen = val;
             }
             0x420D => {
                 println!("MEMSEL: #${:X}", val);
                 self.fastrom = (val & 0b1) == 0b1;
             }
-            0x4300...0x4306 => {
+            0x4300...0x437F => {
+                let ch = ((addr >> 4) & 7) as usize;
+                println!("DMA{} ${:X}: #${:X}", ch, addr, val);
                 match addr & 0xF {
-                    0 => {
-                        self.DMAP[0] = DMAControl::from(val);
-                    }
-                    1 => {
-                        println!("BBAD0: {:X}", val);
-                        self.DMA_dest[0] = val;
-                    }
-                    2 => {
-                        println!("A1T0L: {:X}", val);
-                        self.DMA_addr[0] = (self.DMA_addr[0] & 0xFF00) | val as u16;
-                    }
-                    3 => {
-                        println!("A1T0H: {:X}", val);
-                        self.DMA_addr[0] = (self.DMA_addr[0] & 0x00FF) | ((val as u16) << 8);
-                    }
-                    4 => {
-                        println!("T1B0: {:X}", val);
-                        self.DMA_bank[0] = val;
-                    }
-                    5 => {
-                        println!("DAS0L: {:X}", val);
-                        self.DMA_size[0] = (self.DMA_size[0] & 0xFF00) | val as u16;
-                    }
-                    6 => {
-                        println!("DAS0H: {:X}", val);
-                        self.DMA_size[0] = (self.DMA_size[0] & 0x00FF) | ((val as u16) << 8);
-                    }
-                    _ => panic!("DMA: {:X}", addr & 0xF)
+                    0x0 => self.DMAP[ch] = val,
+                    0x1 => self.DMA_dest[ch] = val,
+                    0x2 => self.DMA_addr[ch] = (self.DMA_addr[ch] & 0xFF00) | val as u16,
+                    0x3 => self.DMA_addr[ch] = (self.DMA_addr[ch] & 0x00FF) | ((val as u16) << 8),
+                    0x4 => self.DMA_bank[ch] = val,
+                    0x5 => self.DMA_size[ch] = (self.DMA_size[ch] & 0xFF00) | val as u16,
+                    0x6 => self.DMA_size[ch] = (self.DMA_size[ch] & 0x00FF) | ((val as u16) << 8),
+                    0x7 => self.HDMA_bank[ch] = val,
+                    0x8 => self.HDMA_addr[ch] = (self.HDMA_addr[ch] & 0xFF00) | val as u16,
+                    0x9 => self.HDMA_addr[ch] = (self.HDMA_addr[ch] & 0x00FF) | ((val as u16) << 8),
+                    0xA => self.HDMA_line[ch] = val,
+                    0xB | 0xF => self.DMA_unused[ch] = val,
+                    _ => { }
                 }
             }
-            0x4310...0x4316 => {
-                println!("TODO: DMA ${:X}", addr);
-            }
-            0x4320...0x4326 => {
-                println!("TODO: DMA ${:X}", addr);
-            }
-            0x4330...0x4336 => {
-                println!("TODO: DMA ${:X}", addr);
+            _ => mem.write_u8(addr, bank, val)
+        }
+    }
+
+    pub fn write_u16(&mut self, mem: &mut Memory, addr: u16, bank: u8, val: u16) {
+        self.write_u8(mem, addr + 0, bank, ((val & 0x00FF) >> 0) as u8);
+        self.write_u8(mem, addr + 1, bank, ((val & 0xFF00) >> 8) as u8);
+    }
+
+    pub fn stack_ptr(&self) -> u16 {
+        self.stack_ptr
+    }
+
+    // Every access takes as long as the region it hits, the
+    // Cycles of the instruction left over are internal ones of
+    // 6 master clocks
+    pub fn master_clocks(&mut self, cycles: u8) -> u32 {
+        let clocks = self.bus_clocks.get();
+        let internal = (cycles as u32).saturating_sub(self.bus_accesses.get());
+        self.bus_clocks.set(0);
+        self.bus_accesses.set(0);
+        clocks + internal * 6
+    }
+
+    // How long DMA has kept the CPU off the bus since last asked
+    pub fn take_stall(&mut self) -> u32 {
+        let stall = self.stall;
+        self.stall = 0;
+        stall
+    }
+
+    fn access(&self, addr: u16, bank: u8) {
+        self.bus_clocks.set(self.bus_clocks.get() + self.speed(addr, bank));
+        self.bus_accesses.set(self.bus_accesses.get() + 1);
+    }
+
+    // WRAM and slow ROM take 8 clocks, I/O 6 and the old
+    // Joypad ports at $4000-$41FF 12. MEMSEL only speeds up
+    // ROM in banks $80 and up
+    fn speed(&self, addr: u16, bank: u8) -> u32 {
+        if bank & 0x40 == 0x40 || addr & 0x8000 == 0x8000 {
+            return match bank & 0x80 == 0x80 && self.fastrom {
+                true => 6,
+                false => 8,
+            };
+        }
+
+        match addr {
+            0x0000...0x1FFF | 0x6000...0x7FFF => 8,
+            0x4000...0x41FF => 12,
+            _ => 6,
+        }
+    }
+
+    // The multiplier works one bit per CPU cycle, 8 cycles for
+    // A product and 16 for a quotient, reading early gives the
+    // Partial result. Dividing by 0 leaves a quotient of $FFFF
+    // And the dividend as the remainder.
+    pub fn alu_step(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if self.mul_cycles > 0 {
+                self.mul_cycles -= 1;
+                if self.rddiv & 1 == 1 {
+                    self.rdmpy = self.rdmpy.wrapping_add(self.alu_shift as u16);
+                }
+                self.rddiv >>= 1;
+                self.alu_shift <<= 1;
             }
-            0x4340...0x4346 => {
-                println!("TODO: DMA ${:X}", addr);
+
+            if self.div_cycles > 0 {
+                self.div_cycles -= 1;
+                self.rddiv <<= 1;
+                self.alu_shift >>= 1;
+                if self.rdmpy as u32 >= self.alu_shift {
+                    self.rdmpy -= self.alu_shift as u16;
+                    self.rddiv |= 1;
+                }
             }
-            0x4350...0x4356 => {
-                println!("TODO: DMA ${:X}", addr);
+        }
+    }
+
+    // With NMITIMEN bit 0 set the CPU reads both ports
+    // Itself at the start of V-blank, D0 of each port goes
+    // To JOY1/JOY2 and D1 to JOY3/JOY4
+    pub fn auto_joypad(&mut self, mem: &Memory) {
+        if self.nmitimen & 1 == 0 {
+            return;
+        }
+
+        let mut port1 = mem.port1.borrow_mut();
+        let mut port2 = mem.port2.borrow_mut();
+
+        port1.strobe(true);
+        port2.strobe(true);
+        port1.strobe(false);
+        port2.strobe(false);
+
+        self.joy = [0u16; 4];
+        for _ in 0..16 {
+            let data1 = port1.clock();
+            let data2 = port2.clock();
+            self.joy[0] = (self.joy[0] << 1) | (data1 & 1) as u16;
+            self.joy[1] = (self.joy[1] << 1) | (data2 & 1) as u16;
+            self.joy[2] = (self.joy[2] << 1) | ((data1 >> 1) & 1) as u16;
+            self.joy[3] = (self.joy[3] << 1) | ((data2 >> 1) & 1) as u16;
+        }
+    }
+
+    pub fn vblank(&mut self) {
+        self.nmi_flag.set(true);
+
+        if self.nmitimen & 0x80 == 0x80 {
+            self.nmi_pending = true;
+        }
+    }
+
+    pub fn new_frame(&mut self) {
+        self.nmi_flag.set(false);
+    }
+
+    // Called for every stretch of `line` the beam covers,
+    // Raises TIMEUP if the IRQ position falls inside it
+    pub fn irq_poll(&mut self, line: u16, start: u32, end: u32) {
+        // H-IRQ fires a few dots after HTIME, V-IRQ
+        // Fires at the start of line VTIME
+        let clock = match (self.nmitimen >> 4) & 3 {
+            0 => return,
+            1 => self.htime as u32 * 4 + 14,
+            2 => 10,
+            _ => self.htime as u32 * 4 + 14,
+        };
+
+        if self.nmitimen & 0x20 == 0x20 && line != self.vtime {
+            return;
+        }
+
+        if clock > start && clock <= end {
+            self.timeup.set(true);
+        }
+    }
+
+    pub fn nmi(&mut self, mem: &mut Memory) {
+        println!("NMI");
+        self.interrupt(mem, 0xFFEA, 0xFFFA);
+    }
+
+    pub fn irq(&mut self, mem: &mut Memory) {
+        println!("IRQ");
+        self.interrupt(mem, 0xFFEE, 0xFFFE);
+    }
+
+    fn interrupt(&mut self, mem: &mut Memory, native: u16, emulation: u16) {
+        // Emulation mode has no bank to push
+        if !self.emulation {
+            let pbr = self.pbr;
+            self.push_u8(mem, pbr);
+        }
+
+        let pc = self.pc;
+        let p = self.p_reg;
+        self.push_u16(mem, pc);
+        self.push_u8(mem, p.bits);
+
+        self.p_reg.insert(FLAG_I);
+        self.p_reg.remove(FLAG_D);
+
+        let vector = if self.emulation { emulation } else { native };
+        self.pbr = 0;
+        self.pc = self.read_u16(mem, vector, 0);
+    }
+
+    // Moves one byte of the lowest enabled channel while the
+    // CPU is halted, returns the master clocks it took or 0
+    // Once every channel is done
+    pub fn dma_step(&mut self, mem: &mut Memory) -> u32 {
+        if self.mdmaen == 0 {
+            return 0;
+        }
+
+        let ch = self.mdmaen.trailing_zeros() as usize;
+        let control = DMAControl::from(self.DMAP[ch]);
+        let pattern = control.mode.pattern();
+
+        // 8 clocks to set up the channel and 8 for every byte
+        let mut clocks = 8;
+        if self.dma_index == 0 {
+            clocks += 8;
+        }
+
+        let b_addr = 0x2100 | self.DMA_dest[ch].wrapping_add(pattern[self.dma_index as usize % pattern.len()]) as u16;
+        let a_addr = self.DMA_addr[ch];
+        let bank = self.DMA_bank[ch];
+
+        match control.direction {
+            DMADirection::To => {
+                let val = self.bus_read(mem, a_addr, bank);
+                self.bus_write(mem, b_addr, 0, val);
             }
-            0x4360...0x4366 => {
-                println!("TODO: DMA ${:X}", addr);
+            DMADirection::From => {
+                let val = self.bus_read(mem, b_addr, 0);
+                self.bus_write(mem, a_addr, bank, val);
             }
-            0x4370...0x4376 => self.DMA7[(addr - 0x4370) as usize] = val,
-            0x4372 => {
-                println!("TODO: A1Bx - DMA Source Address ${:X}", addr);
+        }
+
+        match (control.transfer, control.increment) {
+            (DMATransfer::Fixed, _) => { }
+            (_, DMAIncrement::Increment) => self.DMA_addr[ch] = a_addr.wrapping_add(1),
+            (_, DMAIncrement::Decrement) => self.DMA_addr[ch] = a_addr.wrapping_sub(1),
+        }
+
+        // A size of 0 transfers 64K
+        self.dma_index += 1;
+        self.DMA_size[ch] = self.DMA_size[ch].wrapping_sub(1);
+        if self.DMA_size[ch] == 0 {
+            self.mdmaen &= !(1 << ch);
+            self.dma_index = 0;
+        }
+
+        clocks
+    }
+
+    // At the start of the frame every enabled HDMA
+    // Channel restarts its table
+    pub fn hdma_init(&mut self, mem: &mut Memory) {
+        if self.hdmaen != 0 {
+            self.stall += 18;
+        }
+
+        for ch in 0..8 {
+            self.hdma_terminated[ch] = true;
+
+            if self.hdmaen & (1 << ch) != 0 {
+                self.HDMA_addr[ch] = self.DMA_addr[ch];
+                self.hdma_reload(mem, ch);
             }
-            0x4373 => {
-                println!("TODO: A1Bx - DMA Source Address ${:X}", addr);
+        }
+    }
+
+    // Read the next line counter, and the data address
+    // For indirect tables, a counter of 0 ends the table
+    fn hdma_reload(&mut self, mem: &mut Memory, ch: usize) {
+        let control = DMAControl::from(self.DMAP[ch]);
+        let bank = self.DMA_bank[ch];
+        let addr = self.HDMA_addr[ch];
+
+        let line = self.bus_read(mem, addr, bank);
+        self.HDMA_line[ch] = line;
+        self.HDMA_addr[ch] = addr.wrapping_add(1);
+        self.stall += 8;
+
+        if let HDMAAddressing::Indirect = control.hdma_mode {
+            self.stall += 16;
+            let addr = self.HDMA_addr[ch];
+            self.DMA_size[ch] = (self.bus_read(mem, addr, bank) as u16) |
+                ((self.bus_read(mem, addr.wrapping_add(1), bank) as u16) << 8);
+            self.HDMA_addr[ch] = addr.wrapping_add(2);
+        }
+
+        self.hdma_terminated[ch] = line == 0;
+        self.hdma_transfer[ch] = true;
+    }
+
+    // Runs in H-blank, transfers one unit per active channel
+    pub fn hdma_run(&mut self, mem: &mut Memory) {
+        if (0..8).any(|ch| self.hdmaen & (1 << ch) != 0 && !self.hdma_terminated[ch]) {
+            self.stall += 18;
+        }
+
+        for ch in 0..8 {
+            if self.hdmaen & (1 << ch) == 0 || self.hdma_terminated[ch] {
+                continue;
             }
-            0x4374 => {
-                println!("TODO: A1Bx - DMA Source Address ${:X}", addr);
+
+            let control = DMAControl::from(self.DMAP[ch]);
+            self.stall += 8;
+
+            if self.hdma_transfer[ch] {
+                for &offset in control.mode.pattern() {
+                    self.stall += 8;
+                    let b_addr = 0x2100 | self.DMA_dest[ch].wrapping_add(offset) as u16;
+
+                    let (a_addr, bank) = match control.hdma_mode {
+                        HDMAAddressing::Direct => {
+                            let addr = self.HDMA_addr[ch];
+                            self.HDMA_addr[ch] = addr.wrapping_add(1);
+                            (addr, self.DMA_bank[ch])
+                        }
+                        HDMAAddressing::Indirect => {
+                            let addr = self.DMA_size[ch];
+                            self.DMA_size[ch] = addr.wrapping_add(1);
+                            (addr, self.HDMA_bank[ch])
+                        }
+                    };
+
+                    match control.direction {
+                        DMADirection::To => {
+                            let val = self.bus_read(mem, a_addr, bank);
+                            self.bus_write(mem, b_addr, 0, val);
+                        }
+                        DMADirection::From => {
+                            let val = self.bus_read(mem, b_addr, 0);
+                            self.bus_write(mem, a_addr, bank, val);
+                        }
+                    }
+                }
             }
-            0x4375 => {
-                println!("TODO: A1Bx - DMA Source Address ${:X}", addr);
+
+            // Bit 7 of the line counter repeats the transfer every line
+            self.HDMA_line[ch] = self.HDMA_line[ch].wrapping_sub(1);
+            self.hdma_transfer[ch] = self.HDMA_line[ch] & 0x80 == 0x80;
+
+            if self.HDMA_line[ch] & 0x7F == 0 {
+                self.hdma_reload(mem, ch);
             }
-            _ => mem.write_u8(addr, val)
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn write_u16(&mut self, mem: &mut Memory, addr: u16, val: u16) {
-        self.write_u8(mem, addr + 0, ((val & 0x00FF) >> 0) as u8);
-        self.write_u8(mem, addr + 1, ((val & 0xFF00) >> 8) as u8);
+    fn setup() -> (Ricoh5A22, Memory) {
+        let cart = SnesCart::new(vec![0u8; 0x8000]);
+        (Default::default(), Memory::new(cart))
     }
 
-    pub fn stack_ptr(&self) -> u16 {
-        self.stack_ptr
+    fn result(cpu: &Ricoh5A22, mem: &Memory, addr: u16) -> u16 {
+        cpu.read_u16(mem, addr, 0)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn multiply() {
+        let (mut cpu, mut mem) = setup();
+        cpu.write_u8(&mut mem, 0x4202, 0, 0xFF);
+        cpu.write_u8(&mut mem, 0x4203, 0, 0xFE);
+
+        // After 4 cycles only the low 4 bits of WRMPYA are in
+        cpu.alu_step(4);
+        assert_eq!(result(&cpu, &mem, 0x4216), 0xFE * 0x0F);
+
+        cpu.alu_step(4);
+        assert_eq!(result(&cpu, &mem, 0x4216), 0xFF * 0xFE);
+    }
+
+    #[test]
+    fn divide() {
+        let (mut cpu, mut mem) = setup();
+        cpu.write_u8(&mut mem, 0x4204, 0, 0xE8);
+        cpu.write_u8(&mut mem, 0x4205, 0, 0x03);
+        cpu.write_u8(&mut mem, 0x4206, 0, 7);
+        cpu.alu_step(16);
+
+        assert_eq!(result(&cpu, &mem, 0x4214), 1000 / 7);
+        assert_eq!(result(&cpu, &mem, 0x4216), 1000 % 7);
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        let (mut cpu, mut mem) = setup();
+        cpu.write_u8(&mut mem, 0x4204, 0, 0x34);
+        cpu.write_u8(&mut mem, 0x4205, 0, 0x12);
+        cpu.write_u8(&mut mem, 0x4206, 0, 0);
+        cpu.alu_step(16);
+
+        assert_eq!(result(&cpu, &mem, 0x4214), 0xFFFF);
+        assert_eq!(result(&cpu, &mem, 0x4216), 0x1234);
+    }
+
+    // A write while busy clears the product but doesn't restart it
+    #[test]
+    fn busy_write() {
+        let (mut cpu, mut mem) = setup();
+        cpu.write_u8(&mut mem, 0x4202, 0, 3);
+        cpu.write_u8(&mut mem, 0x4203, 0, 5);
+        cpu.alu_step(2);
+        assert_eq!(result(&cpu, &mem, 0x4216), 15);
+
+        cpu.write_u8(&mut mem, 0x4203, 0, 9);
+        cpu.alu_step(6);
+        assert_eq!(result(&cpu, &mem, 0x4216), 0);
+    }
+}
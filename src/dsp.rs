@@ -0,0 +1,719 @@
+use std::cmp;
+
+// Voice registers, at $x0-$x9 for voice x
+pub const V_VOLL: usize = 0x0;
+pub const V_VOLR: usize = 0x1;
+pub const V_PITCHL: usize = 0x2;
+pub const V_PITCHH: usize = 0x3;
+pub const V_SRCN: usize = 0x4;
+pub const V_ADSR1: usize = 0x5;
+pub const V_ADSR2: usize = 0x6;
+pub const V_GAIN: usize = 0x7;
+const V_ENVX: usize = 0x8;
+const V_OUTX: usize = 0x9;
+
+// Global registers
+const R_MVOLL: usize = 0x0C;
+const R_MVOLR: usize = 0x1C;
+const R_KON: usize = 0x4C;
+const R_KOFF: usize = 0x5C;
+const R_FLG: usize = 0x6C;
+const R_ENDX: usize = 0x7C;
+const R_PMON: usize = 0x2D;
+const R_NON: usize = 0x3D;
+pub const R_DIR: usize = 0x5D;
+const R_EVOLL: usize = 0x2C;
+const R_EVOLR: usize = 0x3C;
+const R_EFB: usize = 0x0D;
+const R_EON: usize = 0x4D;
+const R_ESA: usize = 0x6D;
+const R_EDL: usize = 0x7D;
+// FIR coefficient n is at $nF
+const R_FIR: usize = 0x0F;
+
+const BRR_BLOCK_SIZE: u16 = 9;
+const BRR_BUF_SIZE: usize = 12;
+
+// The envelope and noise rates all come from one counter
+// That runs down from 30720 every sample
+const COUNTER_RANGE: u32 = 2048 * 5 * 3;
+
+static COUNTER_RATES: [u32; 32] = [
+    COUNTER_RANGE + 1, // Never fires
+          2048, 1536,
+    1280, 1024,  768,
+     640,  512,  384,
+     320,  256,  192,
+     160,  128,   96,
+      80,   64,   48,
+      40,   32,   24,
+      20,   16,   12,
+      10,    8,    6,
+       5,    4,    3,
+             2,
+             1,
+];
+
+static COUNTER_OFFSETS: [u32; 32] = [
+       1, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+     536, 0, 1040,
+          0,
+          0,
+];
+
+// Half of the interpolation kernel, the other half is its mirror
+static GAUSS: [i16; 512] = [
+       0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,
+       1,    1,    1,    1,    1,    1,    1,    1,    1,    1,    1,    2,    2,    2,    2,    2,
+       2,    2,    3,    3,    3,    3,    3,    4,    4,    4,    4,    4,    5,    5,    5,    5,
+       6,    6,    6,    6,    7,    7,    7,    8,    8,    8,    9,    9,    9,   10,   10,   10,
+      11,   11,   11,   12,   12,   13,   13,   14,   14,   15,   15,   15,   16,   16,   17,   17,
+      18,   19,   19,   20,   20,   21,   21,   22,   23,   23,   24,   24,   25,   26,   27,   27,
+      28,   29,   29,   30,   31,   32,   32,   33,   34,   35,   36,   36,   37,   38,   39,   40,
+      41,   42,   43,   44,   45,   46,   47,   48,   49,   50,   51,   52,   53,   54,   55,   56,
+      58,   59,   60,   61,   62,   64,   65,   66,   67,   69,   70,   71,   73,   74,   76,   77,
+      78,   80,   81,   83,   84,   86,   87,   89,   90,   92,   94,   95,   97,   99,  100,  102,
+     104,  106,  107,  109,  111,  113,  115,  117,  118,  120,  122,  124,  126,  128,  130,  132,
+     134,  137,  139,  141,  143,  145,  147,  150,  152,  154,  156,  159,  161,  163,  166,  168,
+     171,  173,  175,  178,  180,  183,  186,  188,  191,  193,  196,  199,  201,  204,  207,  210,
+     212,  215,  218,  221,  224,  227,  230,  233,  236,  239,  242,  245,  248,  251,  254,  257,
+     260,  263,  267,  270,  273,  276,  280,  283,  286,  290,  293,  297,  300,  304,  307,  311,
+     314,  318,  321,  325,  328,  332,  336,  339,  343,  347,  351,  354,  358,  362,  366,  370,
+     374,  378,  381,  385,  389,  393,  397,  401,  405,  410,  414,  418,  422,  426,  430,  434,
+     439,  443,  447,  451,  456,  460,  464,  469,  473,  477,  482,  486,  491,  495,  499,  504,
+     508,  513,  517,  522,  527,  531,  536,  540,  545,  550,  554,  559,  563,  568,  573,  577,
+     582,  587,  592,  596,  601,  606,  611,  615,  620,  625,  630,  635,  640,  644,  649,  654,
+     659,  664,  669,  674,  678,  683,  688,  693,  698,  703,  708,  713,  718,  723,  728,  732,
+     737,  742,  747,  752,  757,  762,  767,  772,  777,  782,  787,  792,  797,  802,  806,  811,
+     816,  821,  826,  831,  836,  841,  846,  851,  855,  860,  865,  870,  875,  880,  884,  889,
+     894,  899,  904,  908,  913,  918,  923,  927,  932,  937,  941,  946,  951,  955,  960,  965,
+     969,  974,  978,  983,  988,  992,  997, 1001, 1005, 1010, 1014, 1019, 1023, 1027, 1032, 1036,
+    1040, 1045, 1049, 1053, 1057, 1061, 1066, 1070, 1074, 1078, 1082, 1086, 1090, 1094, 1098, 1102,
+    1106, 1109, 1113, 1117, 1121, 1125, 1128, 1132, 1136, 1139, 1143, 1146, 1150, 1153, 1157, 1160,
+    1164, 1167, 1170, 1174, 1177, 1180, 1183, 1186, 1190, 1193, 1196, 1199, 1202, 1205, 1207, 1210,
+    1213, 1216, 1219, 1221, 1224, 1227, 1229, 1232, 1234, 1237, 1239, 1241, 1244, 1246, 1248, 1251,
+    1253, 1255, 1257, 1259, 1261, 1263, 1265, 1267, 1269, 1270, 1272, 1274, 1275, 1277, 1279, 1280,
+    1282, 1283, 1284, 1286, 1287, 1288, 1290, 1291, 1292, 1293, 1294, 1295, 1296, 1297, 1297, 1298,
+    1299, 1300, 1300, 1301, 1302, 1302, 1303, 1303, 1303, 1304, 1304, 1304, 1304, 1304, 1305, 1305,
+];
+
+#[inline]
+fn clamp16(val: i32) -> i32 {
+    cmp::max(-0x8000, cmp::min(0x7FFF, val))
+}
+
+fn read_u16(aram: &[u8], addr: usize) -> u16 {
+    (aram[addr & 0xFFFF] as u16) | ((aram[(addr + 1) & 0xFFFF] as u16) << 8)
+}
+
+// One sample of a BRR block from its signed 4 bit value and the two
+// Samples before it, doubled to 16 bits the way the DSP keeps them
+pub fn brr_sample(header: u8, nybble: i32, p1: i32, p2: i32) -> i16 {
+    let shift = (header >> 4) as i32;
+    let filter = header & 0x0C;
+
+    let mut s = (nybble << shift) >> 1;
+    if shift >= 0xD {
+        s = if s < 0 { -0x800 } else { 0 };
+    }
+
+    let p2 = p2 >> 1;
+    match filter {
+        0x0 => { }
+        0x4 => {
+            s += p1 >> 1;
+            s += (-p1) >> 5;
+        }
+        0x8 => {
+            s += p1;
+            s -= p2;
+            s += p2 >> 4;
+            s += (p1 * -3) >> 6;
+        }
+        _ => {
+            s += p1;
+            s -= p2;
+            s += (p1 * -13) >> 7;
+            s += (p2 * 3) >> 4;
+        }
+    }
+
+    (clamp16(s) * 2) as i16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeMode {
+    Release,
+    Attack,
+    Decay,
+    Sustain,
+}
+
+impl Default for EnvelopeMode {
+    fn default() -> EnvelopeMode {
+        EnvelopeMode::Release
+    }
+}
+
+// What the debugger and the window show of a voice
+#[derive(Debug, Clone, Copy)]
+pub struct VoiceState {
+    pub env: u8,
+    pub mode: EnvelopeMode,
+    pub pitch: u16,
+    pub srcn: u8,
+    pub keyed: bool,
+    pub echo: bool,
+}
+
+pub const VOICE_STATE_INIT: VoiceState = VoiceState {
+    env: 0,
+    mode: EnvelopeMode::Release,
+    pitch: 0,
+    srcn: 0,
+    keyed: false,
+    echo: false,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Voice {
+    // The last 12 decoded samples
+    buf: [i16; 12],
+    buf_pos: usize,
+    // 4.12 fixed point position in the buffer
+    interp_pos: i32,
+    brr_addr: u16,
+    brr_offset: u16,
+    kon_delay: u8,
+    pub env_mode: EnvelopeMode,
+    pub env: i32,
+    hidden_env: i32,
+}
+
+#[derive(Clone)]
+pub struct Dsp {
+    pub regs: Vec<u8>,
+    pub voices: [Voice; 8],
+    counter: u32,
+    noise: i32,
+    every_other: bool,
+    new_kon: u8,
+    kon: u8,
+    koff: u8,
+    // The last 8 echo samples read, for the FIR filter
+    echo_hist: [[i32; 2]; 8],
+    echo_hist_pos: usize,
+    echo_offset: u32,
+    echo_length: u32,
+    esa: u8,
+    echo_flg: u8,
+    // Debugging aids, bit n silences voice n
+    pub mute: u8,
+    pub echo_mute: bool,
+}
+
+impl Dsp {
+    pub fn new() -> Dsp {
+        let mut dsp = Dsp {
+            regs: vec![0u8; 0x80],
+            voices: Default::default(),
+            counter: 0u32,
+            noise: 0x4000,
+            every_other: false,
+            new_kon: 0u8,
+            kon: 0u8,
+            koff: 0u8,
+            echo_hist: [[0i32; 2]; 8],
+            echo_hist_pos: 0,
+            echo_offset: 0u32,
+            echo_length: 0u32,
+            esa: 0u8,
+            echo_flg: 0xE0,
+            mute: 0u8,
+            echo_mute: false,
+        };
+        dsp.reset();
+        dsp
+    }
+
+    pub fn reset(&mut self) {
+        // Soft reset, muted with echo writes off
+        self.regs[R_FLG] = 0xE0;
+
+        self.counter = 0u32;
+        self.noise = 0x4000;
+        self.every_other = false;
+        self.new_kon = 0u8;
+        self.kon = 0u8;
+        self.koff = 0u8;
+        self.echo_offset = 0u32;
+        self.echo_length = 0u32;
+        self.echo_flg = 0xE0;
+
+        for voice in self.voices.iter_mut() {
+            voice.env_mode = EnvelopeMode::Release;
+            voice.env = 0;
+            voice.kon_delay = 0;
+            voice.brr_offset = 1;
+        }
+    }
+
+    // Takes the registers of a snapshot, the voices keyed on in
+    // It start over from the beginning of their samples
+    pub fn load(&mut self, regs: &[u8]) {
+        self.reset();
+
+        for i in 0..0x80 {
+            self.regs[i] = regs[i];
+        }
+        self.new_kon = self.regs[R_KON];
+        self.esa = self.regs[R_ESA];
+        self.echo_flg = self.regs[R_FLG];
+    }
+
+    pub fn read(&self, addr: u8) -> u8 {
+        self.regs[(addr & 0x7F) as usize]
+    }
+
+    pub fn write(&mut self, addr: u8, val: u8) {
+        let addr = addr as usize;
+        match addr {
+            R_KON => self.new_kon = val,
+            // Any write clears ENDX
+            R_ENDX => {
+                self.regs[R_ENDX] = 0;
+                return;
+            }
+            _ => { }
+        }
+
+        self.regs[addr] = val;
+    }
+
+    // Keyed is set from KON until the voice is released
+    pub fn voice_state(&self, v: usize) -> VoiceState {
+        let voice = &self.voices[v];
+        VoiceState {
+            env: (voice.env >> 4) as u8,
+            mode: voice.env_mode,
+            pitch: ((self.vreg(v, V_PITCHL) as u16) | ((self.vreg(v, V_PITCHH) as u16) << 8)) & 0x3FFF,
+            srcn: self.vreg(v, V_SRCN),
+            keyed: voice.kon_delay > 0 || voice.env_mode != EnvelopeMode::Release,
+            echo: self.regs[R_EON] & (1 << v) != 0,
+        }
+    }
+
+    fn vreg(&self, voice: usize, reg: usize) -> u8 {
+        self.regs[(voice << 4) | reg]
+    }
+
+    fn read_counter(&self, rate: usize) -> bool {
+        (self.counter + COUNTER_OFFSETS[rate]) % COUNTER_RATES[rate] == 0
+    }
+
+    // Produces the next 32 kHz stereo sample
+    pub fn run(&mut self, aram: &mut [u8]) -> (i16, i16) {
+        self.counter = match self.counter {
+            0 => COUNTER_RANGE - 1,
+            counter => counter - 1,
+        };
+
+        // The noise is a 15 bit LFSR clocked at the FLG rate
+        if self.read_counter((self.regs[R_FLG] & 0x1F) as usize) {
+            let feedback = (self.noise << 13) ^ (self.noise << 14);
+            self.noise = (feedback & 0x4000) ^ (self.noise >> 1);
+        }
+
+        // KON and KOFF are only looked at every other sample
+        self.every_other = !self.every_other;
+        if self.every_other {
+            self.new_kon &= !self.kon;
+            self.kon = self.new_kon;
+            self.koff = self.regs[R_KOFF];
+        }
+
+        // Where this sample sits in the echo buffer, ESA
+        // Changes only take effect a sample later
+        let echo_ptr = (self.esa as u32 * 0x100 + self.echo_offset) & 0xFFFF;
+        let echo_in = self.echo_read(aram, echo_ptr);
+
+        let mut main_out = [0i32; 2];
+        let mut echo_out = [0i32; 2];
+        let mut last_output = 0i32;
+
+        for v in 0..8 {
+            let output = self.run_voice(v, aram, last_output);
+            let echo = self.regs[R_EON] & (1 << v) != 0;
+            last_output = output;
+
+            // Muted voices still run, pitch modulation hears them
+            if self.mute & (1 << v) != 0 {
+                continue;
+            }
+
+            for ch in 0..2 {
+                let vol = self.vreg(v, V_VOLL + ch) as i8 as i32;
+                let amp = (output * vol) >> 7;
+                main_out[ch] = clamp16(main_out[ch] + amp);
+                if echo {
+                    echo_out[ch] = clamp16(echo_out[ch] + amp);
+                }
+            }
+        }
+
+        let mut out = [0i32; 2];
+        for ch in 0..2 {
+            let mvol = self.regs[if ch == 0 { R_MVOLL } else { R_MVOLR }] as i8 as i32;
+            let evol = match self.echo_mute {
+                true => 0,
+                false => self.regs[if ch == 0 { R_EVOLL } else { R_EVOLR }] as i8 as i32,
+            };
+            out[ch] = clamp16(((main_out[ch] * mvol) >> 7) as i16 as i32 +
+                              ((echo_in[ch] * evol) >> 7) as i16 as i32);
+
+            // Feed the echo back into the buffer
+            let efb = self.regs[R_EFB] as i8 as i32;
+            echo_out[ch] = clamp16(echo_out[ch] + ((echo_in[ch] * efb) >> 7) as i16 as i32) & !1;
+        }
+
+        self.esa = self.regs[R_ESA];
+        if self.echo_offset == 0 {
+            self.echo_length = (self.regs[R_EDL] & 0x0F) as u32 * 0x800;
+        }
+        self.echo_offset += 4;
+        if self.echo_offset >= self.echo_length {
+            self.echo_offset = 0;
+        }
+
+        // The left half is written before FLG is looked at again
+        let flg = self.echo_flg;
+        self.echo_flg = self.regs[R_FLG];
+        self.echo_write(aram, echo_ptr, echo_out[0], flg);
+        self.echo_write(aram, echo_ptr + 2, echo_out[1], self.echo_flg);
+
+        // FLG bit 6 mutes the output
+        if self.regs[R_FLG] & 0x40 == 0x40 {
+            return (0, 0);
+        }
+
+        (out[0] as i16, out[1] as i16)
+    }
+
+    // Runs one voice for one sample and returns its output,
+    // `last_output` is the previous voice for pitch modulation
+    fn run_voice(&mut self, v: usize, aram: &mut [u8], last_output: i32) -> i32 {
+        let vbit = 1u8 << v;
+        let flg = self.regs[R_FLG];
+
+        // The directory holds the start and loop address of each sample,
+        // The start is only used while keying on
+        let entry = self.regs[R_DIR] as usize * 0x100 + self.vreg(v, V_SRCN) as usize * 4;
+        let next_addr = match self.voices[v].kon_delay {
+            0 => read_u16(aram, entry + 2),
+            _ => read_u16(aram, entry),
+        };
+
+        let mut pitch = (self.vreg(v, V_PITCHL) as i32) | ((self.vreg(v, V_PITCHH) as i32 & 0x3F) << 8);
+
+        // Voice 0 can't be modulated
+        if v > 0 && self.regs[R_PMON] & vbit == vbit {
+            pitch += ((last_output >> 5) * pitch) >> 10;
+        }
+
+        let mut header = {
+            let voice = &self.voices[v];
+            aram[voice.brr_addr as usize]
+        };
+        let brr_byte = {
+            let voice = &self.voices[v];
+            aram[voice.brr_addr.wrapping_add(voice.brr_offset) as usize]
+        };
+
+        // Key on takes 5 samples, the envelope stays at 0 and
+        // The last 3 samples fill the BRR buffer
+        if self.voices[v].kon_delay > 0 {
+            let voice = &mut self.voices[v];
+            if voice.kon_delay == 5 {
+                voice.brr_addr = next_addr;
+                voice.brr_offset = 1;
+                voice.buf_pos = 0;
+                header = 0;
+            }
+
+            voice.env = 0;
+            voice.hidden_env = 0;
+
+            voice.kon_delay -= 1;
+            voice.interp_pos = if voice.kon_delay & 3 != 0 { 0x4000 } else { 0 };
+
+            pitch = 0;
+        }
+
+        let mut output = self.interpolate(v);
+        if self.regs[R_NON] & vbit == vbit {
+            output = (self.noise * 2) as i16 as i32;
+        }
+
+        let output = ((output * self.voices[v].env) >> 11) & !1;
+        let envx = (self.voices[v].env >> 4) as u8;
+
+        {
+            let voice = &mut self.voices[v];
+
+            // Soft reset and the end of a non-looping sample silence the voice
+            if flg & 0x80 == 0x80 || header & 3 == 1 {
+                voice.env_mode = EnvelopeMode::Release;
+                voice.env = 0;
+            }
+
+            if self.every_other {
+                if self.koff & vbit == vbit {
+                    voice.env_mode = EnvelopeMode::Release;
+                }
+                if self.kon & vbit == vbit {
+                    voice.kon_delay = 5;
+                    voice.env_mode = EnvelopeMode::Attack;
+                }
+            }
+        }
+
+        if self.voices[v].kon_delay == 0 {
+            self.run_envelope(v);
+        }
+
+        // Decode the next 4 samples once the previous ones are used up
+        let mut looped = 0u8;
+        if self.voices[v].interp_pos >= 0x4000 {
+            let next_byte = {
+                let voice = &self.voices[v];
+                aram[voice.brr_addr.wrapping_add(voice.brr_offset + 1) as usize]
+            };
+            self.decode_brr(v, header, ((brr_byte as u16) << 8) | next_byte as u16);
+
+            let voice = &mut self.voices[v];
+            voice.brr_offset += 2;
+            if voice.brr_offset >= BRR_BLOCK_SIZE {
+                voice.brr_addr = voice.brr_addr.wrapping_add(BRR_BLOCK_SIZE);
+                if header & 1 == 1 {
+                    voice.brr_addr = next_addr;
+                    looped = vbit;
+                }
+                voice.brr_offset = 1;
+            }
+        }
+
+        {
+            let voice = &mut self.voices[v];
+            voice.interp_pos = cmp::min((voice.interp_pos & 0x3FFF) + pitch, 0x7FFF);
+        }
+
+        let mut endx = self.regs[R_ENDX] | looped;
+        if self.voices[v].kon_delay == 5 {
+            endx &= !vbit;
+        }
+        self.regs[R_ENDX] = endx;
+
+        self.regs[(v << 4) | V_ENVX] = envx;
+        self.regs[(v << 4) | V_OUTX] = (output >> 8) as u8;
+
+        output
+    }
+
+    // Reads the next echo sample and runs the history through the
+    // 8 tap FIR filter, C0 applies to the oldest sample
+    fn echo_read(&mut self, aram: &[u8], ptr: u32) -> [i32; 2] {
+        self.echo_hist_pos = (self.echo_hist_pos + 1) % 8;
+
+        for ch in 0..2 {
+            let sample = read_u16(aram, (ptr + ch as u32 * 2) as usize) as i16 as i32;
+            self.echo_hist[self.echo_hist_pos][ch] = sample >> 1;
+        }
+
+        let mut echo_in = [0i32; 2];
+        for ch in 0..2 {
+            let tap = |i: usize| {
+                let sample = self.echo_hist[(self.echo_hist_pos + 1 + i) % 8][ch];
+                (sample * self.regs[R_FIR + i * 0x10] as i8 as i32) >> 6
+            };
+
+            // The first 7 taps wrap around, only the last one clamps
+            let mut sum = 0i32;
+            for i in 0..7 {
+                sum += tap(i);
+            }
+            sum = sum as i16 as i32 + tap(7) as i16 as i32;
+            echo_in[ch] = clamp16(sum) & !1;
+        }
+
+        echo_in
+    }
+
+    // The echo buffer lives in ARAM and nothing stops it from
+    // Running over code or samples, games rely on it either way
+    fn echo_write(&self, aram: &mut [u8], ptr: u32, val: i32, flg: u8) {
+        // FLG bit 5 disables echo writes
+        if flg & 0x20 == 0x20 {
+            return;
+        }
+
+        aram[(ptr & 0xFFFF) as usize] = val as u8;
+        aram[((ptr + 1) & 0xFFFF) as usize] = (val >> 8) as u8;
+    }
+
+    fn interpolate(&self, v: usize) -> i32 {
+        let voice = &self.voices[v];
+        let offset = ((voice.interp_pos >> 4) & 0xFF) as usize;
+        let pos = (voice.interp_pos >> 12) as usize + voice.buf_pos;
+        let sample = |i: usize| voice.buf[(pos + i) % BRR_BUF_SIZE] as i32;
+
+        let mut out = (GAUSS[255 - offset] as i32 * sample(0)) >> 11;
+        out += (GAUSS[511 - offset] as i32 * sample(1)) >> 11;
+        out += (GAUSS[256 + offset] as i32 * sample(2)) >> 11;
+        out = out as i16 as i32;
+        out += (GAUSS[offset] as i32 * sample(3)) >> 11;
+
+        clamp16(out) & !1
+    }
+
+    // 4 nybbles of a BRR block, shifted by the header range
+    // And run through the filter it selects
+    fn decode_brr(&mut self, v: usize, header: u8, nybbles: u16) {
+        let voice = &mut self.voices[v];
+        let mut nybbles = nybbles;
+
+        for _ in 0..4 {
+            let pos = voice.buf_pos;
+            let s = ((nybbles as i16) >> 12) as i32;
+            nybbles <<= 4;
+
+            let p1 = voice.buf[(pos + BRR_BUF_SIZE - 1) % BRR_BUF_SIZE] as i32;
+            let p2 = voice.buf[(pos + BRR_BUF_SIZE - 2) % BRR_BUF_SIZE] as i32;
+
+            voice.buf[pos] = brr_sample(header, s, p1, p2);
+            voice.buf_pos = (pos + 1) % BRR_BUF_SIZE;
+        }
+    }
+
+    fn run_envelope(&mut self, v: usize) {
+        let adsr1 = self.vreg(v, V_ADSR1);
+        let adsr2 = self.vreg(v, V_ADSR2);
+        let gain = self.vreg(v, V_GAIN);
+
+        let mode = self.voices[v].env_mode;
+        let mut env = self.voices[v].env;
+
+        if mode == EnvelopeMode::Release {
+            self.voices[v].env = cmp::max(env - 0x8, 0);
+            return;
+        }
+
+        let rate;
+        let env_data;
+        if adsr1 & 0x80 == 0x80 {
+            env_data = adsr2;
+            if mode == EnvelopeMode::Attack {
+                rate = ((adsr1 & 0x0F) * 2 + 1) as usize;
+                env += if rate < 31 { 0x20 } else { 0x400 };
+            } else {
+                env -= 1;
+                env -= env >> 8;
+                rate = match mode {
+                    EnvelopeMode::Decay => (((adsr1 >> 3) & 0x0E) + 0x10) as usize,
+                    _ => (adsr2 & 0x1F) as usize,
+                };
+            }
+        } else {
+            env_data = gain;
+            match gain >> 5 {
+                // Direct
+                0...3 => {
+                    env = gain as i32 * 0x10;
+                    rate = 31;
+                }
+                // Linear decrease
+                4 => {
+                    env -= 0x20;
+                    rate = (gain & 0x1F) as usize;
+                }
+                // Exponential decrease
+                5 => {
+                    env -= 1;
+                    env -= env >> 8;
+                    rate = (gain & 0x1F) as usize;
+                }
+                // Linear and bent line increase
+                mode => {
+                    env += 0x20;
+                    // Compared unsigned, a hidden envelope left negative
+                    // By a linear decrease counts as past the bend
+                    if mode == 7 && (self.voices[v].hidden_env as u32) >= 0x600 {
+                        env += 0x8 - 0x20;
+                    }
+                    rate = (gain & 0x1F) as usize;
+                }
+            }
+        }
+
+        {
+            let voice = &mut self.voices[v];
+
+            // The sustain level is checked even in GAIN mode
+            if (env >> 8) == (env_data >> 5) as i32 && voice.env_mode == EnvelopeMode::Decay {
+                voice.env_mode = EnvelopeMode::Sustain;
+            }
+
+            voice.hidden_env = env;
+
+            if env < 0 || env > 0x7FF {
+                env = if env < 0 { 0 } else { 0x7FF };
+                if voice.env_mode == EnvelopeMode::Attack {
+                    voice.env_mode = EnvelopeMode::Decay;
+                }
+            }
+        }
+
+        if self.read_counter(rate) {
+            self.voices[v].env = env;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brr_shift() {
+        assert_eq!(brr_sample(0xC0, 1, 0, 0), 4096);
+        assert_eq!(brr_sample(0xC0, -8, 0, 0), -32768);
+        assert_eq!(brr_sample(0x00, 7, 0, 0), 6);
+        // Shifts past 12 leave 0 or -2048
+        assert_eq!(brr_sample(0xD0, 7, 0, 0), 0);
+        assert_eq!(brr_sample(0xF0, -1, 0, 0), -4096);
+    }
+
+    #[test]
+    fn brr_filters() {
+        // Filter 1 is 15/16 of the last sample
+        assert_eq!(brr_sample(0x04, 0, 1000, 0), 936);
+        // Filter 2 is 61/32 of the last less 15/16 of the one before
+        assert_eq!(brr_sample(0x08, 0, 1000, 400), 1530);
+        // Filter 3 is 115/64 of the last less 13/16 of the one before
+        assert_eq!(brr_sample(0x0C, 0, 1000, 400), 1470);
+    }
+
+    #[test]
+    fn brr_clamps() {
+        // Clamped to 16 bits before doubling, which then wraps
+        assert_eq!(brr_sample(0xC8, 7, 32767, 0), -2);
+        assert_eq!(brr_sample(0xC4, 7, 32767, 0), -6146);
+    }
+}
@@ -0,0 +1,598 @@
+use scrn::Scrn;
+
+use std::sync::atomic::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::cmp;
+
+bitflags! {
+    #[derive(Default)]
+    pub flags Buttons: u16 {
+        const BUTTON_R      = 0b0000000000010000,
+        const BUTTON_L      = 0b0000000000100000,
+        const BUTTON_X      = 0b0000000001000000,
+        const BUTTON_A      = 0b0000000010000000,
+        const BUTTON_RIGHT  = 0b0000000100000000,
+        const BUTTON_LEFT   = 0b0000001000000000,
+        const BUTTON_DOWN   = 0b0000010000000000,
+        const BUTTON_UP     = 0b0000100000000000,
+        const BUTTON_START  = 0b0001000000000000,
+        const BUTTON_SELECT = 0b0010000000000000,
+        const BUTTON_Y      = 0b0100000000000000,
+        const BUTTON_B      = 0b1000000000000000,
+    }
+}
+
+// Anything plugged into a controller port
+pub trait Controller {
+    // The latch line, bit 0 of $4016 drives it on both ports
+    fn strobe(&mut self, latch: bool);
+
+    // Clock one bit out, bits 0 and 1 are the D0 and D1 lines
+    fn clock(&mut self) -> u8;
+
+    // The IO line of the port, driven by WRIO
+    fn iobit(&mut self, _high: bool) { }
+
+    // Where a light gun sees the beam, in dots and lines. When
+    // The beam gets there the gun pulls the IO line low.
+    fn sensor(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send>;
+}
+
+impl Clone for Box<Controller + Send> {
+    fn clone(&self) -> Box<Controller + Send> {
+        self.box_clone()
+    }
+}
+
+// Builds the device named on the command line, players are
+// Handed out in port order. Returns the players it took.
+pub fn device(name: &str, player: usize) -> (Box<Controller + Send>, usize) {
+    match name {
+        "pad" => (Box::new(Joypad::new(player)), 1),
+        "multitap" => (Box::new(Multitap::new(player)), 4),
+        "mouse" => (Box::new(Mouse::new()), 0),
+        "superscope" => (Box::new(SuperScope::new()), 0),
+        "justifier" => (Box::new(Justifier::new(false)), 0),
+        "justifiers" => (Box::new(Justifier::new(true)), 0),
+        _ => (Box::new(Unplugged), 0),
+    }
+}
+
+// An empty port, both data lines read 0
+#[derive(Debug, Clone)]
+pub struct Unplugged;
+
+impl Controller for Unplugged {
+    fn strobe(&mut self, _latch: bool) { }
+
+    fn clock(&mut self) -> u8 {
+        0u8
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send> {
+        Box::new(self.clone())
+    }
+}
+
+// The standard pad, 12 buttons shifted out B first
+// Followed by the 4 ID bits which are 0
+#[derive(Debug, Clone)]
+pub struct Joypad {
+    player: usize,
+    latched: bool,
+    shift: u16,
+}
+
+impl Joypad {
+    pub fn new(player: usize) -> Joypad {
+        Joypad {
+            player: player,
+            latched: false,
+            shift: 0u16,
+        }
+    }
+
+    fn buttons(&self) -> u16 {
+        unsafe { Scrn::JOYPAD[self.player] }
+    }
+}
+
+impl Controller for Joypad {
+    fn strobe(&mut self, latch: bool) {
+        self.latched = latch;
+        if latch {
+            self.shift = self.buttons();
+        }
+    }
+
+    fn clock(&mut self) -> u8 {
+        // While latched the pad keeps reporting B
+        if self.latched {
+            self.shift = self.buttons();
+        }
+
+        let bit = (self.shift >> 15) as u8;
+
+        // Once the 16 bits are out the line stays high
+        self.shift = (self.shift << 1) | 1;
+        bit
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send> {
+        Box::new(self.clone())
+    }
+}
+
+// Four pads on one port. With the IO line high the first two
+// Pads come out on D0/D1, with it low the other two do. While
+// Latched D1 is held high so games can find the tap.
+#[derive(Debug, Clone)]
+pub struct Multitap {
+    pads: [Joypad; 4],
+    latched: bool,
+    io: bool,
+}
+
+impl Multitap {
+    pub fn new(player: usize) -> Multitap {
+        Multitap {
+            pads: [
+                Joypad::new(player + 0),
+                Joypad::new(player + 1),
+                Joypad::new(player + 2),
+                Joypad::new(player + 3),
+            ],
+            latched: false,
+            io: true,
+        }
+    }
+}
+
+impl Controller for Multitap {
+    fn strobe(&mut self, latch: bool) {
+        self.latched = latch;
+        for pad in self.pads.iter_mut() {
+            pad.strobe(latch);
+        }
+    }
+
+    fn clock(&mut self) -> u8 {
+        if self.latched {
+            return (self.pads[0].clock() & 1) | 2;
+        }
+
+        let (d0, d1) = if self.io { (0, 1) } else { (2, 3) };
+        (self.pads[d0].clock() & 1) | ((self.pads[d1].clock() & 1) << 1)
+    }
+
+    fn iobit(&mut self, high: bool) {
+        self.io = high;
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send> {
+        Box::new(self.clone())
+    }
+}
+
+pub const MOUSE_LEFT: u8 = 0x40;
+pub const MOUSE_RIGHT: u8 = 0x80;
+
+// The SNES Mouse sends 32 bits, a 0 byte, the buttons with the
+// Sensitivity and the 0001 signature, then Y and X as sign and
+// Magnitude. Clocking it while latched cycles the sensitivity.
+#[derive(Debug, Clone)]
+pub struct Mouse {
+    latched: bool,
+    speed: u8,
+    shift: u32,
+}
+
+impl Mouse {
+    pub fn new() -> Mouse {
+        Mouse {
+            latched: false,
+            speed: 0u8,
+            shift: 0u32,
+        }
+    }
+
+    // Motion since the last latch, faster sensitivities scale it up
+    fn axis(&self, delta: isize) -> u8 {
+        let delta = match self.speed {
+            0 => delta,
+            1 => delta * 3 / 2,
+            _ => delta * 2,
+        };
+
+        let magnitude = cmp::min(delta.abs(), 127) as u8;
+        if delta < 0 { 0x80 | magnitude } else { magnitude }
+    }
+}
+
+impl Controller for Mouse {
+    fn strobe(&mut self, latch: bool) {
+        self.latched = latch;
+        if latch {
+            let dx = Scrn::MOUSE_X.swap(0, Ordering::SeqCst);
+            let dy = Scrn::MOUSE_Y.swap(0, Ordering::SeqCst);
+            let buttons = unsafe { Scrn::MOUSE_BUTTONS } & (MOUSE_LEFT | MOUSE_RIGHT);
+
+            // Negative is up and left, which the mouse sends as 1
+            let status = buttons | (self.speed << 4) | 0x01;
+            self.shift = ((status as u32) << 16) |
+                         ((self.axis(dy) as u32) << 8) |
+                         ((self.axis(dx) as u32) << 0);
+        }
+    }
+
+    fn clock(&mut self) -> u8 {
+        if self.latched {
+            self.speed = (self.speed + 1) % 3;
+            return 0u8;
+        }
+
+        let bit = (self.shift >> 31) as u8;
+        self.shift = (self.shift << 1) | 1;
+        bit
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send> {
+        Box::new(self.clone())
+    }
+}
+
+pub const GUN_TRIGGER: u8 = 0x01;
+pub const GUN_CURSOR: u8 = 0x02;
+pub const GUN_TURBO: u8 = 0x04;
+pub const GUN_PAUSE: u8 = 0x08;
+
+// The sensor reacts a little after the pixel is drawn,
+// Games calibrate for it
+const GUN_DOTS: isize = 40;
+
+fn gun_position(gun: usize) -> Option<(isize, isize)> {
+    let (x, y) = unsafe { (Scrn::GUN_X[gun], Scrn::GUN_Y[gun]) };
+
+    let height = unsafe { Scrn::FRAME_HEIGHT };
+    let lines = if height > 240 { height / 2 } else { height };
+
+    if x < 0 || y < 0 || x >= 256 || y >= lines as isize {
+        None
+    } else {
+        Some((x, y))
+    }
+}
+
+fn gun_sensor(gun: usize) -> Option<(u16, u16)> {
+    gun_position(gun).map(|(x, y)| ((x + GUN_DOTS) as u16, (y + 1) as u16))
+}
+
+fn gun_buttons(gun: usize) -> u8 {
+    unsafe { Scrn::GUN_BUTTONS[gun] }
+}
+
+// 8 bits, fire, cursor, turbo, pause, 2 unused, offscreen and
+// Noise, then the 1s of the ID. Turbo is a switch, without it
+// The trigger only fires once per pull. Pause fires once per press.
+#[derive(Debug, Clone)]
+pub struct SuperScope {
+    latched: bool,
+    counter: u8,
+    trigger: bool,
+    cursor: bool,
+    turbo: bool,
+    pause: bool,
+    offscreen: bool,
+    trigger_lock: bool,
+    turbo_lock: bool,
+    pause_lock: bool,
+}
+
+impl SuperScope {
+    pub fn new() -> SuperScope {
+        SuperScope {
+            latched: false,
+            counter: 0u8,
+            trigger: false,
+            cursor: false,
+            turbo: false,
+            pause: false,
+            offscreen: true,
+            trigger_lock: false,
+            turbo_lock: false,
+            pause_lock: false,
+        }
+    }
+
+    fn sample(&mut self) {
+        let buttons = gun_buttons(0);
+
+        let turbo = buttons & GUN_TURBO == GUN_TURBO;
+        if turbo && !self.turbo_lock {
+            self.turbo = !self.turbo;
+        }
+        self.turbo_lock = turbo;
+
+        let trigger = buttons & GUN_TRIGGER == GUN_TRIGGER;
+        self.trigger = trigger && (self.turbo || !self.trigger_lock);
+        self.trigger_lock = trigger;
+
+        let pause = buttons & GUN_PAUSE == GUN_PAUSE;
+        self.pause = pause && !self.pause_lock;
+        self.pause_lock = pause;
+
+        self.cursor = buttons & GUN_CURSOR == GUN_CURSOR;
+        self.offscreen = gun_position(0).is_none();
+    }
+}
+
+impl Controller for SuperScope {
+    fn strobe(&mut self, latch: bool) {
+        if self.latched != latch {
+            self.latched = latch;
+            self.counter = 0;
+        }
+    }
+
+    fn clock(&mut self) -> u8 {
+        if self.counter >= 8 {
+            return 1;
+        }
+
+        if self.counter == 0 {
+            self.sample();
+        }
+
+        let bit = match self.counter {
+            0 => self.trigger,
+            1 => self.cursor,
+            2 => self.turbo,
+            3 => self.pause,
+            6 => self.offscreen,
+            _ => false,
+        };
+
+        self.counter += 1;
+        bit as u8
+    }
+
+    fn sensor(&self) -> Option<(u16, u16)> {
+        gun_sensor(0)
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send> {
+        Box::new(self.clone())
+    }
+}
+
+// 32 bits, 12 zeros, the ID, both triggers, both starts, and
+// Which gun the sensor follows. The guns take turns every
+// Latch, a lone gun skips every other frame.
+#[derive(Debug, Clone)]
+pub struct Justifier {
+    chained: bool,
+    latched: bool,
+    counter: u8,
+    active: usize,
+    buttons: [u8; 2],
+}
+
+static JUSTIFIER_ID: [u8; 12] = [1, 1, 1, 0, 0, 1, 0, 1, 0, 1, 0, 1];
+
+impl Justifier {
+    pub fn new(chained: bool) -> Justifier {
+        Justifier {
+            chained: chained,
+            latched: false,
+            counter: 0u8,
+            active: 0,
+            buttons: [0u8; 2],
+        }
+    }
+}
+
+impl Controller for Justifier {
+    fn strobe(&mut self, latch: bool) {
+        if self.latched != latch {
+            self.latched = latch;
+            self.counter = 0;
+
+            if !latch {
+                self.active ^= 1;
+            }
+        }
+    }
+
+    fn clock(&mut self) -> u8 {
+        if self.counter >= 32 {
+            return 1;
+        }
+
+        if self.counter == 0 {
+            self.buttons[0] = gun_buttons(0);
+            self.buttons[1] = if self.chained { gun_buttons(1) } else { 0 };
+        }
+
+        let bit = match self.counter {
+            0...11 => 0,
+            12...23 => JUSTIFIER_ID[(self.counter - 12) as usize],
+            24 => (self.buttons[0] & GUN_TRIGGER) as u8,
+            25 => (self.buttons[1] & GUN_TRIGGER) as u8,
+            26 => ((self.buttons[0] & GUN_PAUSE) >> 3) as u8,
+            27 => ((self.buttons[1] & GUN_PAUSE) >> 3) as u8,
+            28 => self.active as u8,
+            _ => 0,
+        };
+
+        self.counter += 1;
+        bit
+    }
+
+    fn sensor(&self) -> Option<(u16, u16)> {
+        if self.active == 1 && !self.chained {
+            return None;
+        }
+
+        gun_sensor(self.active)
+    }
+
+    fn box_clone(&self) -> Box<Controller + Send> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ScriptInput {
+    Pad(usize, u16),
+    Mouse(isize, isize, u8),
+    Gun(usize, isize, isize, u8),
+}
+
+// Input for running without a window, one event per line:
+//   <frame> pad <player> [buttons...]
+//   <frame> mouse <dx> <dy> [L] [R]
+//   <frame> gun <n> <x> <y> [TRIGGER] [CURSOR] [TURBO] [PAUSE]
+// Pad buttons stay held until the next pad line for that player.
+#[derive(Debug, Clone)]
+pub struct InputScript {
+    events: Vec<(u64, ScriptInput)>,
+    next: usize,
+}
+
+impl InputScript {
+    pub fn load(path: &str) -> Result<InputScript, String> {
+        let file = File::open(path).map_err(|err| format!("Could not open input script: {}", err))?;
+
+        let mut events = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|err| format!("Could not read input script: {}", err))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match InputScript::parse(line) {
+                Some(event) => events.push(event),
+                None => return Err(format!("Bad input script line {}: {}", number + 1, line)),
+            }
+        }
+
+        events.sort_by_key(|&(frame, _)| frame);
+
+        Ok(InputScript {
+            events: events,
+            next: 0,
+        })
+    }
+
+    fn parse(line: &str) -> Option<(u64, ScriptInput)> {
+        let split: Vec<&str> = line.split_whitespace().collect();
+        if split.len() < 3 {
+            return None;
+        }
+
+        let frame = match split[0].parse::<u64>() {
+            Ok(frame) => frame,
+            Err(_) => return None,
+        };
+
+        match split[1] {
+            "pad" => {
+                let player = match split[2].parse::<usize>() {
+                    Ok(player) if player < 8 => player,
+                    _ => return None,
+                };
+
+                let mut buttons = Buttons::empty();
+                for name in &split[3..] {
+                    buttons.insert(match &*name.to_uppercase() {
+                        "B" => BUTTON_B,
+                        "Y" => BUTTON_Y,
+                        "SELECT" => BUTTON_SELECT,
+                        "START" => BUTTON_START,
+                        "UP" => BUTTON_UP,
+                        "DOWN" => BUTTON_DOWN,
+                        "LEFT" => BUTTON_LEFT,
+                        "RIGHT" => BUTTON_RIGHT,
+                        "A" => BUTTON_A,
+                        "X" => BUTTON_X,
+                        "L" => BUTTON_L,
+                        "R" => BUTTON_R,
+                        _ => return None,
+                    });
+                }
+
+                Some((frame, ScriptInput::Pad(player, buttons.bits())))
+            }
+            "mouse" if split.len() >= 4 => {
+                let (dx, dy) = match (split[2].parse::<isize>(), split[3].parse::<isize>()) {
+                    (Ok(dx), Ok(dy)) => (dx, dy),
+                    _ => return None,
+                };
+
+                let mut buttons = 0u8;
+                for name in &split[4..] {
+                    buttons |= match &*name.to_uppercase() {
+                        "L" => MOUSE_LEFT,
+                        "R" => MOUSE_RIGHT,
+                        _ => return None,
+                    };
+                }
+
+                Some((frame, ScriptInput::Mouse(dx, dy, buttons)))
+            }
+            "gun" if split.len() >= 5 => {
+                let gun = match split[2].parse::<usize>() {
+                    Ok(gun) if gun < 2 => gun,
+                    _ => return None,
+                };
+
+                let (x, y) = match (split[3].parse::<isize>(), split[4].parse::<isize>()) {
+                    (Ok(x), Ok(y)) => (x, y),
+                    _ => return None,
+                };
+
+                let mut buttons = 0u8;
+                for name in &split[5..] {
+                    buttons |= match &*name.to_uppercase() {
+                        "TRIGGER" => GUN_TRIGGER,
+                        "CURSOR" => GUN_CURSOR,
+                        "TURBO" => GUN_TURBO,
+                        "PAUSE" | "START" => GUN_PAUSE,
+                        _ => return None,
+                    };
+                }
+
+                Some((frame, ScriptInput::Gun(gun, x, y, buttons)))
+            }
+            _ => None,
+        }
+    }
+
+    // Feed everything due by this frame to the input state
+    pub fn apply(&mut self, frame: u64) {
+        while self.next < self.events.len() && self.events[self.next].0 <= frame {
+            match self.events[self.next].1 {
+                ScriptInput::Pad(player, buttons) => unsafe {
+                    Scrn::JOYPAD[player] = buttons;
+                },
+                ScriptInput::Mouse(dx, dy, buttons) => {
+                    Scrn::MOUSE_X.fetch_add(dx, Ordering::SeqCst);
+                    Scrn::MOUSE_Y.fetch_add(dy, Ordering::SeqCst);
+                    unsafe { Scrn::MOUSE_BUTTONS = buttons; }
+                }
+                ScriptInput::Gun(gun, x, y, buttons) => unsafe {
+                    Scrn::GUN_X[gun] = x;
+                    Scrn::GUN_Y[gun] = y;
+                    Scrn::GUN_BUTTONS[gun] = buttons;
+                },
+            }
+            self.next += 1;
+        }
+    }
+}
@@ -14,6 +14,8 @@ extern crate clap;
 extern crate clock_ticks;
 extern crate minifb;
 
+use minifb::Scale;
+
 use std::io::{self, Read, BufRead, Write};
 use std::fs::File;
 
@@ -24,6 +26,16 @@ mod scrn;
 mod regs;
 mod cpu;
 mod mem;
+mod ppu;
+mod timing;
+mod input;
+mod apu;
+mod dsp;
+mod audio;
+mod spc;
+mod rip;
+mod coprocessor;
+mod superfx;
 
 use cart::{SnesCart, SnesHeader};
 use snes::SNES;
@@ -36,6 +48,7 @@ const AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
 use std::thread;
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 
 pub fn cpu_loop<F>(rate: u64, mut callback: F)
     where F: FnMut() -> State + Send + 'static
@@ -77,10 +90,45 @@ fn main() {
         (version: VERSION)
         (author: AUTHORS)
         (about: "SNES Emulator written in Rust")
-        (@arg INPUT: +required "Sets the ROM file to emulate")
+        (@arg INPUT: "Sets the ROM file to emulate")
+        (@arg PORT1: --port1 +takes_value possible_value[pad multitap mouse none] "Sets the device in port 1 (default: pad)")
+        (@arg PORT2: --port2 +takes_value possible_value[pad multitap mouse superscope justifier justifiers none] "Sets the device in port 2 (default: pad)")
+        (@arg HEADLESS: --headless "Runs without a window")
+        (@arg SCRIPT: --script +takes_value "Feeds controller input from a script file")
+        (@arg WAV: --wav +takes_value "Records the sound to a .wav file")
+        (@arg RATE: --rate +takes_value "Sets the audio sample rate, 32000, 44100 or 48000 (default: 44100)")
+        (@subcommand spc =>
+            (about: "Renders an .spc music file to a .wav file")
+            (@arg FILE: +required "Sets the SPC file to play")
+            (@arg OUTPUT: -o --output +takes_value "Sets the .wav file to write (default: FILE with .wav)")
+            (@arg LENGTH: --length +takes_value "Seconds to play before fading out (default: from the tags)")
+            (@arg FADE: --fade +takes_value "Length of the fade-out in ms (default: from the tags)")
+            (@arg RATE: --rate +takes_value "Sets the audio sample rate, 32000, 44100 or 48000 (default: 44100)")
+        )
+        (@subcommand rip =>
+            (about: "Extracts the BRR samples of an .spc music file")
+            (@arg FILE: +required "Sets the SPC file to rip")
+            (@arg OUTPUT: -o --output +takes_value "Sets the directory to write to (default: FILE without the extension)")
+            (@arg LENGTH: --length +takes_value "Seconds to play while watching the voices (default: 30)")
+        )
     ).get_matches();
 
-    let rom_path = matches.value_of("INPUT").unwrap();
+    if let Some(matches) = matches.subcommand_matches("spc") {
+        play_spc(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("rip") {
+        rip_spc(matches);
+        return;
+    }
+
+    let rom_path = match matches.value_of("INPUT") {
+        Some(path) => path,
+        None => {
+            println!("{}", matches.usage());
+            return;
+        }
+    };
     println!("Opening ROM: {}", rom_path);
 
     let mut rom_raw = Vec::<u8>::new();
@@ -96,11 +144,40 @@ fn main() {
     let mut snes = SNES::new(rom_raw);
     println!("Done");
 
+    let (port1, players) = input::device(matches.value_of("PORT1").unwrap_or("pad"), 0);
+    let (port2, _) = input::device(matches.value_of("PORT2").unwrap_or("pad"), players);
+    *snes.mem.port1.borrow_mut() = port1;
+    *snes.mem.port2.borrow_mut() = port2;
+
+    if let Some(path) = matches.value_of("SCRIPT") {
+        match input::InputScript::load(path) {
+            Ok(script) => snes.script = Some(script),
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    let headless = matches.is_present("HEADLESS");
+
+    // Without a window or a file there's nowhere for the sound to go
+    let rate = sample_rate(&matches);
+    if let Some(path) = matches.value_of("WAV") {
+        match audio::WavSink::create(path, rate) {
+            Ok(wav) => *snes.audio.lock().unwrap() = Some(audio::sink(rate, Box::new(wav))),
+            Err(err) => panic!("Could not create {}: {}", path, err)
+        }
+    } else if headless {
+        *snes.audio.lock().unwrap() = Some(Box::new(audio::NullSink));
+    }
+
+    // The debugger may still be running when the window closes,
+    // The sink is only ever touched with the lock held
+    let audio = snes.audio.clone();
+
     let stdin = io::stdin();
 
     let mut bp = Vec::<u16>::new();
 
-    std::thread::spawn(move || {
+    let debugger = std::thread::spawn(move || {
         loop {
             print!(">> ");
             io::stdout().flush().expect("Error flushing stdout");
@@ -145,8 +222,37 @@ fn main() {
                     println!("]");
                     println!(" {}{:04X}: ^", "   ".repeat((cpu.stack_ptr() & 0xF) as usize), cpu.stack_ptr());
                 }
+                "f" => {
+                    match snes.run_frame() {
+                        Ok(_) => println!("Frame {}", snes.frames),
+                        Err(err) => {
+                            println!("{}", err);
+                            println!("{:?}", Ricoh5A22::from(snes.clone()));
+                        }
+                    }
+                }
                 "c" => println!("{:?}", Ricoh5A22::from(snes.clone())),
                 "h" => println!("{:?}", SnesHeader::from(SnesCart::from(snes.clone()))),
+                "vs" => {
+                    let mute = Scrn::VOICE_MUTE.load(Ordering::SeqCst);
+                    println!("V  ENV  MODE     PITCH SRCN KEY ECHO MUTE");
+                    for v in 0..8 {
+                        let state = snes.mem.apu.dsp.voice_state(v);
+                        println!("{}  {:3}  {:8} {:04X}  {:02X}   {:3} {:4} {}", v, state.env, format!("{:?}", state.mode),
+                                 state.pitch, state.srcn, if state.keyed { "on" } else { "off" },
+                                 if state.echo { "on" } else { "off" }, if mute & (1 << v) != 0 { "yes" } else { "no" });
+                    }
+                    println!("Echo muted: {}", Scrn::ECHO_MUTE.load(Ordering::SeqCst));
+                }
+                "echo" => {
+                    scrn::toggle_echo_mute();
+                    println!("Echo muted: {}", Scrn::ECHO_MUTE.load(Ordering::SeqCst));
+                }
+                "unmute" => {
+                    Scrn::VOICE_MUTE.store(0, Ordering::SeqCst);
+                    Scrn::ECHO_MUTE.store(false, Ordering::SeqCst);
+                    println!("All voices unmuted");
+                }
                 _ => {
                     let split: Vec<&str> = line.trim().split(' ').collect();
                     match split[0] {
@@ -173,13 +279,40 @@ fn main() {
                             unsafe { print!("{:04X}", Scrn::VRAM[((addr & 0xFFF0) | 0xF) as usize]); }
                             println!("]");
                         }
+                        "spc" => {
+                            let header = SnesHeader::from(SnesCart::from(snes.clone()));
+                            let tags = spc::tags(&header, unsafe { Scrn::FRAME_COUNT });
+                            match spc::Spc::from_apu(&snes.mem.apu, tags).save(split[1]) {
+                                Ok(_) => println!("SPC saved to: {}", split[1]),
+                                Err(err) => println!("{}", err)
+                            }
+                        }
+                        "rc" => {
+                            let clocks = split[1].parse::<u64>().unwrap();
+                            match snes.run_cycles(clocks) {
+                                Ok(ran) => println!("Ran {} master clocks", ran),
+                                Err(err) => {
+                                    println!("{}", err);
+                                    println!("{:?}", Ricoh5A22::from(snes.clone()));
+                                }
+                            }
+                        }
+                        "mute" | "solo" => {
+                            match split[1].parse::<usize>() {
+                                Ok(voice) if voice < 8 => {
+                                    if split[0] == "mute" { scrn::toggle_mute(voice) } else { scrn::toggle_solo(voice) }
+                                    println!("Muted voices: {:08b}", Scrn::VOICE_MUTE.load(Ordering::SeqCst));
+                                }
+                                _ => println!("No such voice: {}", split[1])
+                            }
+                        }
                         "vc" => {
-                            let addr = u16::from_str_radix(split[1], 16).unwrap();
-                            print!("{:04X}: [", (addr & 0xFFF0));
-                            for i in (addr & 0xFFF0)...((addr & 0xFFF0) | 0xE) {
-                                unsafe { print!("{:02X} ", Scrn::PALETTE[i as usize]); }
+                            let addr = u16::from_str_radix(split[1], 16).unwrap() & 0xFF;
+                            print!("{:02X}: [", (addr & 0xF0));
+                            for i in (addr & 0xF0)...((addr & 0xF0) | 0xE) {
+                                unsafe { print!("{:04X} ", Scrn::CGRAM[i as usize]); }
                             }
-                            unsafe { print!("{:02X}", Scrn::VRAM[((addr & 0xFFF0) | 0xF) as usize]); }
+                            unsafe { print!("{:04X}", Scrn::CGRAM[((addr & 0xF0) | 0xF) as usize]); }
                             println!("]");
                         }
                         _ => print!("Unknown command: {}", line)
@@ -189,9 +322,121 @@ fn main() {
         }
     });
 
-    let screen = Screen::new(String::from("snes-emu"), 256,224);
+    // Without a window the debugger owns the process
+    if headless {
+        unsafe { Scrn::RUNNING = true; }
+        debugger.join().unwrap();
+        finish_audio(&audio);
+        return;
+    }
+
+    let screen = Screen::new_scaled(String::from("snes-emu"), 512, 478, Scale::X1);
 
     unsafe {
         Scrn::SCREEN = Some(screen);
     }
-}
\ No newline at end of file
+
+    finish_audio(&audio);
+}
+
+fn finish_audio(audio: &audio::SharedSink) {
+    if let Some(mut sink) = audio.lock().unwrap().take() {
+        sink.finish();
+    }
+}
+
+fn sample_rate(matches: &clap::ArgMatches) -> u32 {
+    match matches.value_of("RATE").unwrap_or("44100") {
+        "32000" => 32000,
+        "44100" => 44100,
+        "48000" => 48000,
+        rate => panic!("Unsupported sample rate: {}", rate)
+    }
+}
+
+fn play_spc(matches: &clap::ArgMatches) {
+    let path = matches.value_of("FILE").unwrap();
+    println!("Opening SPC: {}", path);
+
+    let spc = match spc::Spc::load(path) {
+        Ok(spc) => spc,
+        Err(err) => panic!("{}", err)
+    };
+
+    let tags = &spc.tags;
+    println!("Song: {}", tags.song);
+    println!("Game: {}", tags.game);
+    println!("Artist: {}", tags.artist);
+    println!("Dumper: {}", tags.dumper);
+    println!("Date: {}", tags.date);
+    println!("Comments: {}", tags.comments);
+    if !tags.ost_title.is_empty() {
+        println!("Soundtrack: {} disc {} track {}", tags.ost_title, tags.ost_disc, tags.ost_track >> 8);
+    }
+    if !tags.publisher.is_empty() {
+        println!("Publisher: {} {}", tags.publisher, tags.copyright);
+    }
+
+    let seconds = match matches.value_of("LENGTH") {
+        Some(length) => length.parse::<u32>().expect("Bad length"),
+        None if tags.seconds != 0 => tags.seconds,
+        None => spc::DEFAULT_SECONDS,
+    };
+    let fade = match matches.value_of("FADE") {
+        Some(fade) => fade.parse::<u32>().expect("Bad fade"),
+        None if tags.fade != 0 => tags.fade,
+        None => spc::DEFAULT_FADE,
+    };
+
+    let output = match matches.value_of("OUTPUT") {
+        Some(output) => String::from(output),
+        None => format!("{}.wav", file_stem(path)),
+    };
+
+    let rate = sample_rate(matches);
+    let wav = match audio::WavSink::create(&output, rate) {
+        Ok(wav) => wav,
+        Err(err) => panic!("Could not create {}: {}", output, err)
+    };
+    let mut sink = audio::sink(rate, Box::new(wav));
+
+    println!("Rendering {}s with a {}ms fade to {}", seconds, fade, output);
+    let checksum = spc::render(&spc, &mut *sink, seconds, fade);
+    println!("Done, checksum {:08X}", checksum);
+}
+
+fn rip_spc(matches: &clap::ArgMatches) {
+    let path = matches.value_of("FILE").unwrap();
+    println!("Opening SPC: {}", path);
+
+    let spc = match spc::Spc::load(path) {
+        Ok(spc) => spc,
+        Err(err) => panic!("{}", err)
+    };
+
+    let seconds = match matches.value_of("LENGTH") {
+        Some(length) => length.parse::<u32>().expect("Bad length"),
+        None => 30,
+    };
+    let output = match matches.value_of("OUTPUT") {
+        Some(output) => String::from(output),
+        None => String::from(file_stem(path)),
+    };
+
+    println!("Playing {}s", seconds);
+    let rip = rip::Rip::run(&spc, seconds);
+
+    let used = rip.samples.iter().filter(|s| s.used).count();
+    println!("Found {} samples, {} played", rip.samples.len(), used);
+    match rip.save(&output, &spc.tags.game) {
+        Ok(_) => println!("Samples saved to: {}", output),
+        Err(err) => panic!("Could not save samples: {}", err)
+    }
+}
+
+fn file_stem(path: &str) -> &str {
+    match path.rfind('.') {
+        Some(dot) => &path[..dot],
+        None => path,
+    }
+}
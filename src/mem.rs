@@ -1,25 +1,58 @@
 use cart::SnesCart;
+use ppu::Ppu;
+use apu::Apu;
+use input::{Controller, Joypad};
+use coprocessor::Coprocessor;
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 
 #[derive(Clone)]
 pub struct Memory {
     cart: SnesCart,
-    wram: Cell<[u8; 0x2000]>,
+    wram: Vec<u8>,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub open_bus: Cell<u8>,
+    pub port1: RefCell<Box<Controller + Send>>,
+    pub port2: RefCell<Box<Controller + Send>>,
+    pub coprocessor: RefCell<Option<Box<Coprocessor + Send>>>,
 }
 
 impl Memory {
     pub fn new(cart: SnesCart) -> Memory {
         Memory {
             cart: cart,
-            wram: Cell::new([0x55u8; 0x2000]),
+            wram: vec![0x55u8; 0x20000],
+            ppu: Default::default(),
+            apu: Apu::new(),
+            open_bus: Cell::new(0u8),
+            port1: RefCell::new(Box::new(Joypad::new(0))),
+            port2: RefCell::new(Box::new(Joypad::new(1))),
+            coprocessor: RefCell::new(None),
+        }
+    }
+
+    pub fn coprocessor_irq(&self) -> bool {
+        match *self.coprocessor.borrow() {
+            Some(ref chip) => chip.irq(),
+            None => false,
         }
     }
 
     pub fn peek_u8(&self, addr: u16, bank: u8) -> u8 {
+        if let Some(ref mut chip) = *self.coprocessor.borrow_mut() {
+            if let Some(val) = chip.read(addr, bank, self.open_bus.get()) {
+                return val;
+            }
+        }
+
         let addr = addr as usize;
         match addr {
-            0x0000...0x1FFF => self.wram.get()[addr],
+            // Banks $7E and $7F are all WRAM, the first 8K of it
+            // Is mirrored into the low banks
+            _ if bank & 0xFE == 0x7E => self.wram[((bank as usize & 1) << 16) | addr],
+            0x0000...0x1FFF => self.wram[addr],
+            0x2100...0x213F => self.ppu.read_u8(addr as u16, self.open_bus.get()),
             0x8000...0xFFFF => {
                 self.cart[(addr - 0x8000) + (bank as usize * 0x8000)]
             }
@@ -27,11 +60,19 @@ impl Memory {
         }
     }
 
-    pub fn write_u8(&mut self, addr: u16, val: u8) {
+    pub fn write_u8(&mut self, addr: u16, bank: u8, val: u8) {
+        if let Some(ref mut chip) = *self.coprocessor.borrow_mut() {
+            if chip.write(addr, bank, val) {
+                return;
+            }
+        }
+
         let addr = addr as usize;
         match addr {
-            0x0000...0x1FFF => self.wram.get_mut()[addr] = val,
-            _ => panic!("Unsupported memory write at: ${:X} with value: ${:X}", addr, val)
+            _ if bank & 0xFE == 0x7E => self.wram[((bank as usize & 1) << 16) | addr] = val,
+            0x0000...0x1FFF => self.wram[addr] = val,
+            0x2100...0x2133 => self.ppu.write_u8(addr as u16, val),
+            _ => panic!("Unsupported memory write at: ${:X}:{:X} with value: ${:X}", bank, addr, val)
         }
     }
 }
\ No newline at end of file
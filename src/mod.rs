@@ -4,4 +4,14 @@ pub use self::inst::*;
 pub use self::scrn::*;
 pub use self::regs::*;
 pub use self::cpu::*;
-pub use self::mem::*;
\ No newline at end of file
+pub use self::mem::*;
+pub use self::ppu::*;
+pub use self::timing::*;
+pub use self::input::*;
+pub use self::apu::*;
+pub use self::dsp::*;
+pub use self::audio::*;
+pub use self::spc::*;
+pub use self::rip::*;
+pub use self::coprocessor::*;
+pub use self::superfx::*;
\ No newline at end of file
@@ -0,0 +1,825 @@
+use regs::*;
+use std::cell::Cell;
+use std::cmp;
+use scrn::{get_color, get_brightness, Scrn, FRAME_STRIDE};
+use timing::Timing;
+
+// Layer draw order per BG mode, front to back, as (BG, priority bit)
+static MODE0_ORDER: [(usize, bool); 8] = [(0, true), (1, true), (0, false), (1, false), (2, true), (3, true), (2, false), (3, false)];
+static MODE1_ORDER: [(usize, bool); 6] = [(0, true), (1, true), (0, false), (1, false), (2, true), (2, false)];
+static MODE1_BG3_ORDER: [(usize, bool); 6] = [(2, true), (0, true), (1, true), (0, false), (1, false), (2, false)];
+static MODE2_ORDER: [(usize, bool); 4] = [(0, true), (1, true), (0, false), (1, false)];
+static MODE6_ORDER: [(usize, bool); 2] = [(0, true), (0, false)];
+static MODE7_EXTBG_ORDER: [(usize, bool); 3] = [(1, true), (0, false), (1, false)];
+
+pub const SCREEN_WIDTH: usize = 256;
+
+// Chip versions reported by STAT77/STAT78
+const PPU1_VERSION: u8 = 1;
+const PPU2_VERSION: u8 = 3;
+
+#[derive(Debug, Clone, Default)]
+pub struct Ppu {
+    pub timing: Timing,
+    pub inidisp: u8,
+    pub MOSAIC: MOSAIC,
+    pub VMAIN: VMAIN,
+    pub BGMODE: BGMODE,
+    pub BGXSC: [BGXSC; 4],
+    pub BG12NBA: BGNBA,
+    pub BG34NBA: BGNBA,
+    pub BGHOFS: [u16; 4],
+    pub BGVOFS: [u16; 4],
+    pub M7SEL: M7SEL,
+    pub M7A: u16,
+    pub M7B: u16,
+    pub M7C: u16,
+    pub M7D: u16,
+    pub M7X: u16,
+    pub M7Y: u16,
+    pub M7HOFS: u16,
+    pub M7VOFS: u16,
+    pub TM: SCRDES,
+    pub TS: SCRDES,
+    pub SETINI: SETINI,
+    pub fixed_color: u16,
+    pub cgwsel: u8,
+    pub range_over: bool,
+    pub time_over: bool,
+    frame_hires: bool,
+    bgofs_latch: u8,
+    bghofs_latch: u8,
+    m7_latch: u8,
+    mosaic_vcounter: u16,
+    cgram_latch: u8,
+    ophct: Cell<u16>,
+    opvct: Cell<u16>,
+    ophct_flip: Cell<bool>,
+    opvct_flip: Cell<bool>,
+    counter_latch: Cell<bool>,
+    ppu1_mdr: Cell<u8>,
+    ppu2_mdr: Cell<u8>,
+    // The EXTLATCH pin, wired to bit 7 of WRIO
+    pub extlatch: bool,
+}
+
+impl Ppu {
+    pub fn reset(&mut self) {
+        // Set the display off (forced blank) with 0 brightness
+        self.inidisp = 0x80u8;
+
+        self.VMAIN = Default::default();
+
+        // WRIO comes up as $FF
+        self.extlatch = true;
+
+        self.timing.reset();
+    }
+
+    pub fn write_u8(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x2100 => {
+                println!("INIDISP: #${:X}", val);
+                self.inidisp = val;
+            }
+            0x2101...0x2104 => {
+                println!("TODO: REGISTERS 0x2101...0x2120 ({:X})", addr);
+            }
+            0x2105 => {
+                println!("BGMODE: #${:X}", val);
+                self.BGMODE = BGMODE::from(val);
+                println!("{:?}", self.BGMODE);
+            }
+            0x2106 => {
+                println!("MOSAIC: #${:X}", val);
+                self.MOSAIC = MOSAIC::from(val);
+                println!("{:?}", self.MOSAIC);
+            }
+            0x2107...0x210A => {
+                let bg = (addr - 0x2107) as usize;
+                println!("BG{}SC: #${:X}", bg + 1, val);
+                self.BGXSC[bg] = BGXSC::from(val);
+                println!("BG{}SC: {:?}", bg + 1, self.BGXSC[bg]);
+            }
+            0x210B => {
+                println!("BG12NBA: #${:X}", val);
+                self.BG12NBA = BGNBA::from(val);
+                println!("BG12NBA: {:?}", self.BG12NBA);
+            }
+            0x210C => {
+                println!("BG34NBA: #${:X}", val);
+                self.BG34NBA = BGNBA::from(val);
+                println!("BG34NBA: {:?}", self.BG34NBA);
+            }
+            0x210D...0x2114 => {
+                let bg = ((addr - 0x210D) / 2) as usize;
+
+                // BG1 scroll doubles as the Mode 7 scroll, which
+                // Goes through the Mode 7 latch instead
+                if addr == 0x210D {
+                    self.M7HOFS = self.m7_word(val) & 0x1FFF;
+                } else if addr == 0x210E {
+                    self.M7VOFS = self.m7_word(val) & 0x1FFF;
+                }
+
+                match addr & 1 {
+                    // BGnHOFS, keeps the fine scroll of the previous write
+                    1 => {
+                        self.BGHOFS[bg] = (((val as u16) << 8) | ((self.bgofs_latch & !7) as u16) | ((self.bghofs_latch & 7) as u16)) & 0x3FF;
+                        self.bgofs_latch = val;
+                        self.bghofs_latch = val;
+                        println!("BG{}HOFS: {:X}", bg + 1, self.BGHOFS[bg]);
+                    }
+                    // BGnVOFS
+                    _ => {
+                        self.BGVOFS[bg] = (((val as u16) << 8) | (self.bgofs_latch as u16)) & 0x3FF;
+                        self.bgofs_latch = val;
+                        println!("BG{}VOFS: {:X}", bg + 1, self.BGVOFS[bg]);
+                    }
+                }
+            }
+            0x2115 => {
+                println!("VMAIN: #${:X}", val);
+                self.VMAIN = VMAIN::from(val);
+                println!("{:?}", self.VMAIN);
+            }
+            0x2116 => {
+                println!("VMADDL: #${:X}", val);
+                unsafe { Scrn::VRAM_ADDR = (Scrn::VRAM_ADDR & 0xFF00) | val as u16; }
+                self.vram_prefetch();
+            }
+            0x2117 => {
+                println!("VMADDH: #${:X}", val);
+                unsafe { Scrn::VRAM_ADDR = (Scrn::VRAM_ADDR & 0x00FF) | ((val as u16) << 8); }
+                self.vram_prefetch();
+            }
+            0x2118 => {
+                if self.vram_writable() {
+                    let addr = self.vram_addr();
+                    unsafe { Scrn::VRAM[addr] = (Scrn::VRAM[addr] & 0xFF00) | val as u16; }
+                }
+
+                if self.VMAIN.increment == VINC::Byte {
+                    self.vram_increment();
+                }
+            }
+            0x2119 => {
+                if self.vram_writable() {
+                    let addr = self.vram_addr();
+                    unsafe { Scrn::VRAM[addr] = (Scrn::VRAM[addr] & 0x00FF) | ((val as u16) << 8); }
+                }
+
+                if self.VMAIN.increment == VINC::Word {
+                    self.vram_increment();
+                }
+            }
+            0x211A => {
+                println!("M7SEL: #${:X}", val);
+                self.M7SEL = M7SEL::from(val);
+                println!("{:?}", self.M7SEL);
+            }
+            0x211B => self.M7A = self.m7_word(val),
+            0x211C => self.M7B = self.m7_word(val),
+            0x211D => self.M7C = self.m7_word(val),
+            0x211E => self.M7D = self.m7_word(val),
+            0x211F => self.M7X = self.m7_word(val) & 0x1FFF,
+            0x2120 => self.M7Y = self.m7_word(val) & 0x1FFF,
+            0x2121 => {
+                println!("CGADD: {:X}", val);
+                unsafe {
+                    Scrn::CGRAM_ADDR = val as u16 * 2;
+                }
+            }
+            0x2122 => {
+                println!("CGDATA: {:X}", val);
+                // The low byte is held in a latch until the high
+                // Byte comes in, then the whole color is written
+                unsafe {
+                    match Scrn::CGRAM_ADDR & 1 {
+                        0 => self.cgram_latch = val,
+                        _ => {
+                            let index = (Scrn::CGRAM_ADDR >> 1) as usize;
+                            Scrn::CGRAM[index] = (((val & 0x7F) as u16) << 8) | self.cgram_latch as u16;
+                        }
+                    }
+                    Scrn::CGRAM_ADDR = (Scrn::CGRAM_ADDR + 1) & 0x1FF;
+                }
+            }
+            0x2123...0x212B => {
+                println!("TODO: REGISTERS 0x2123...0x2133 ({:X})", addr);
+            }
+            0x212C => {
+                println!("TM: #${:X}", val);
+                self.TM = SCRDES::from(val);
+                println!("TM: {:?}", self.TM);
+            }
+            0x212D => {
+                println!("TS: #${:X}", val);
+                self.TS = SCRDES::from(val);
+                println!("TS: {:?}", self.TS);
+            }
+            0x212E...0x212F => {
+                println!("TODO: REGISTERS 0x2123...0x2133 ({:X})", addr);
+            }
+            0x2130 => {
+                println!("CGWSEL: #${:X}", val);
+                self.cgwsel = val;
+            }
+            0x2131 => {
+                println!("TODO: REGISTERS 0x2123...0x2133 ({:X})", addr);
+            }
+            0x2132 => {
+                println!("COLDATA: #${:X}", val);
+                let intensity = (val & 0x1F) as u16;
+                if val & 0x20 == 0x20 { self.fixed_color = (self.fixed_color & !0x001F) | (intensity << 00); }
+                if val & 0x40 == 0x40 { self.fixed_color = (self.fixed_color & !0x03E0) | (intensity << 05); }
+                if val & 0x80 == 0x80 { self.fixed_color = (self.fixed_color & !0x7C00) | (intensity << 10); }
+            }
+            0x2133 => {
+                println!("SETINI: #${:X}", val);
+                self.SETINI = SETINI::from(val);
+                self.timing.interlace = self.SETINI.interlace;
+                self.timing.overscan = self.SETINI.overscan;
+                println!("{:?}", self.SETINI);
+            }
+            _ => panic!("Unsupported PPU write at: ${:X} with value: ${:X}", addr, val)
+        }
+    }
+
+    // Write-only and unused registers read back the open bus,
+    // PPU1 and PPU2 each keep the last value they drove on it
+    pub fn read_u8(&self, addr: u16, open_bus: u8) -> u8 {
+        match addr {
+            // Signed 16 bit M7A times the last byte written to M7B
+            0x2134...0x2136 => {
+                let result = (self.M7A as i16 as i32) * ((self.M7B >> 8) as i8 as i32);
+                self.ppu1_bus((result >> ((addr - 0x2134) * 8)) as u8)
+            }
+            0x2137 => {
+                println!("SLHV");
+
+                // Only latches while the pin is held high
+                if self.extlatch {
+                    self.latch_counters();
+                }
+
+                open_bus
+            }
+            0x2138 => {
+                // Nothing drives OAM reads yet, PPU1 open bus it is
+                self.ppu1_mdr.get()
+            }
+            0x2139 => {
+                // Reads return the prefetched word, which is only
+                // Refilled when the address increments
+                let val = unsafe { (Scrn::VRAM_LATCH & 0xFF) as u8 };
+                if self.VMAIN.increment == VINC::Byte {
+                    self.vram_prefetch();
+                    self.vram_increment();
+                }
+                self.ppu1_bus(val)
+            }
+            0x213A => {
+                let val = unsafe { (Scrn::VRAM_LATCH >> 8) as u8 };
+                if self.VMAIN.increment == VINC::Word {
+                    self.vram_prefetch();
+                    self.vram_increment();
+                }
+                self.ppu1_bus(val)
+            }
+            0x213B => {
+                // CGRAM reads alternate between the low
+                // And the high byte of a color
+                let val = unsafe {
+                    let color = Scrn::CGRAM[(Scrn::CGRAM_ADDR >> 1) as usize];
+                    let val = match Scrn::CGRAM_ADDR & 1 {
+                        0 => (color & 0xFF) as u8,
+                        _ => ((color >> 8) & 0x7F) as u8 | (self.ppu2_mdr.get() & 0x80),
+                    };
+                    Scrn::CGRAM_ADDR = (Scrn::CGRAM_ADDR + 1) & 0x1FF;
+                    val
+                };
+                self.ppu2_bus(val)
+            }
+            // The counters are read low byte first through a flip-flop
+            0x213C => {
+                let flip = self.ophct_flip.get();
+                self.ophct_flip.set(!flip);
+                let val = match flip {
+                    false => (self.ophct.get() & 0xFF) as u8,
+                    true => ((self.ophct.get() >> 8) & 0x01) as u8 | (self.ppu2_mdr.get() & 0xFE),
+                };
+                self.ppu2_bus(val)
+            }
+            0x213D => {
+                let flip = self.opvct_flip.get();
+                self.opvct_flip.set(!flip);
+                let val = match flip {
+                    false => (self.opvct.get() & 0xFF) as u8,
+                    true => ((self.opvct.get() >> 8) & 0x01) as u8 | (self.ppu2_mdr.get() & 0xFE),
+                };
+                self.ppu2_bus(val)
+            }
+            0x213E => {
+                let mut val = PPU1_VERSION | (self.ppu1_mdr.get() & 0x10);
+                if self.range_over { val |= 0x40; }
+                if self.time_over { val |= 0x80; }
+                self.ppu1_bus(val)
+            }
+            0x213F => {
+                // Reading STAT78 resets the counter flip-flops
+                // And clears the latch flag
+                self.ophct_flip.set(false);
+                self.opvct_flip.set(false);
+
+                let mut val = PPU2_VERSION | (self.ppu2_mdr.get() & 0x20);
+                if self.timing.pal { val |= 0x10; }
+                if self.counter_latch.get() { val |= 0x40; }
+                if self.timing.field { val |= 0x80; }
+
+                self.counter_latch.set(false);
+                self.ppu2_bus(val)
+            }
+            // Registers decoded by PPU1 return its bus
+            0x2104...0x2106 | 0x2108...0x210A |
+            0x2114...0x2116 | 0x2118...0x211A |
+            0x2124...0x2126 | 0x2128...0x212A => self.ppu1_mdr.get(),
+            _ => open_bus,
+        }
+    }
+
+    fn ppu1_bus(&self, val: u8) -> u8 {
+        self.ppu1_mdr.set(val);
+        val
+    }
+
+    fn ppu2_bus(&self, val: u8) -> u8 {
+        self.ppu2_mdr.set(val);
+        val
+    }
+
+    // Copy the beam position into OPHCT/OPVCT
+    pub fn latch_counters(&self) {
+        self.latch_position(self.timing.hcounter(), self.timing.vcounter);
+    }
+
+    pub fn latch_position(&self, hcounter: u16, vcounter: u16) {
+        self.ophct.set(hcounter);
+        self.opvct.set(vcounter);
+        self.counter_latch.set(true);
+    }
+
+    // VMADD after the address remapping, VRAM is 32K words
+    fn vram_addr(&self) -> usize {
+        unsafe { (self.VMAIN.remap.apply(Scrn::VRAM_ADDR) & 0x7FFF) as usize }
+    }
+
+    fn vram_increment(&self) {
+        unsafe { Scrn::VRAM_ADDR = Scrn::VRAM_ADDR.wrapping_add(self.VMAIN.amount.amount()); }
+    }
+
+    fn vram_prefetch(&self) {
+        let addr = self.vram_addr();
+        unsafe { Scrn::VRAM_LATCH = Scrn::VRAM[addr]; }
+    }
+
+    // Direct color applies to 256 color BGs and Mode 7
+    fn direct_color(&self) -> bool {
+        self.cgwsel & 0x01 == 0x01
+    }
+
+    pub fn forced_blank(&self) -> bool {
+        self.inidisp & 0x80 == 0x80
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.inidisp & 0x0F
+    }
+
+    // VRAM can only be written during V-blank or forced blank,
+    // Writes while the screen is drawn are dropped but the
+    // Address still increments
+    fn vram_writable(&self) -> bool {
+        self.forced_blank() || self.timing.vblank()
+    }
+
+    // Overscan shows 239 lines instead of 224
+    pub fn visible_lines(&self) -> u16 {
+        match self.SETINI.overscan {
+            true => 239,
+            false => 224,
+        }
+    }
+
+    // BG modes 5 and 6 draw 512 pixels per line
+    pub fn hires(&self) -> bool {
+        match self.BGMODE.mode {
+            BGMODES::Mode5 | BGMODES::Mode6 => true,
+            _ => false,
+        }
+    }
+
+    // Publish the finished frame to the frontend
+    pub fn end_frame(&mut self) {
+        let lines = self.visible_lines() as usize;
+
+        unsafe {
+            Scrn::FRAME_WIDTH = if self.frame_hires { 512 } else { 256 };
+            Scrn::FRAME_HEIGHT = if self.SETINI.interlace { lines * 2 } else { lines };
+            Scrn::FRAME_COUNT += 1;
+        }
+
+        self.frame_hires = false;
+    }
+
+    // The sprite overflow flags are cleared when V-blank ends
+    pub fn new_frame(&mut self) {
+        self.range_over = false;
+        self.time_over = false;
+    }
+
+    // The vertical mosaic counter is shared by all BGs and runs
+    // On every line whether mosaic is on or not, it's reloaded at
+    // The start of the frame and whenever a block of lines ends,
+    // So size changes take effect on the next block
+    pub fn mosaic_scanline(&mut self, line: u16) {
+        if line == 1 || self.mosaic_vcounter <= 1 {
+            self.mosaic_vcounter = cmp::max(self.MOSAIC.size, 1);
+        } else {
+            self.mosaic_vcounter -= 1;
+        }
+    }
+
+    // Number of lines since the start of the current mosaic block
+    fn mosaic_voffset(&self) -> u16 {
+        self.MOSAIC.size.saturating_sub(self.mosaic_vcounter)
+    }
+
+    // Move a pixel to the top left of its mosaic block
+    fn mosaic(&self, bg: usize, x: u16, y: u16) -> (u16, u16) {
+        match self.MOSAIC.layer(bg) {
+            true => (x - x % self.MOSAIC.size, y - self.mosaic_voffset()),
+            false => (x, y),
+        }
+    }
+
+    // The Mode 7 registers are written low byte first
+    // Through a latch holding the previous write
+    fn m7_word(&mut self, val: u8) -> u16 {
+        let word = ((val as u16) << 8) | self.m7_latch as u16;
+        self.m7_latch = val;
+        word
+    }
+
+    pub fn render_scanline(&mut self, line: u16) {
+        // Interlaced frames weave the two fields together
+        let row = match self.SETINI.interlace {
+            true => (line as usize - 1) * 2 + self.timing.field as usize,
+            false => line as usize - 1,
+        };
+        let offset = row * FRAME_STRIDE;
+
+        // Forced blank outputs black regardless of brightness
+        if self.forced_blank() {
+            for x in 0..FRAME_STRIDE {
+                unsafe { Scrn::FRAME[offset + x] = 0u32; }
+            }
+            return;
+        }
+
+        let brightness = self.brightness();
+        let hires = self.hires();
+        let pseudo_hires = self.SETINI.pseudo_hires;
+        self.frame_hires |= hires || pseudo_hires;
+
+        // In hi-res the sub screen is output on even dots
+        // And the main screen on odd dots
+        for x in 0..SCREEN_WIDTH as u16 {
+            let (sub, main) = match (hires, pseudo_hires) {
+                (true, _) => (self.sub_pixel(x * 2, line), self.main_pixel(x * 2 + 1, line)),
+                (false, true) => (self.sub_pixel(x, line), self.main_pixel(x, line)),
+                (false, false) => {
+                    let main = self.main_pixel(x, line);
+                    (main, main)
+                }
+            };
+
+            let x = x as usize * 2;
+            unsafe {
+                Scrn::FRAME[offset + x + 0] = get_brightness(get_color(sub), brightness);
+                Scrn::FRAME[offset + x + 1] = get_brightness(get_color(main), brightness);
+            }
+        }
+    }
+
+    fn main_pixel(&self, x: u16, y: u16) -> u16 {
+        match self.screen_pixel(self.TM, x, y) {
+            Some(color) => color,
+            None => cgram_color(0),
+        }
+    }
+
+    // The sub screen backdrop is the fixed color
+    fn sub_pixel(&self, x: u16, y: u16) -> u16 {
+        match self.screen_pixel(self.TS, x, y) {
+            Some(color) => color,
+            None => self.fixed_color,
+        }
+    }
+
+    // Color of the front most pixel of the enabled layers
+    fn screen_pixel(&self, layers: SCRDES, x: u16, y: u16) -> Option<u16> {
+        let mut pixels = [None; 4];
+
+        match self.BGMODE.mode {
+            BGMODES::Mode7 => {
+                if layers.bg1 {
+                    pixels[0] = self.mode7_pixel(0, x, y);
+                }
+                if layers.bg2 && self.SETINI.extbg {
+                    pixels[1] = self.mode7_pixel(1, x, y);
+                }
+            }
+            _ => {
+                for bg in 0..4 {
+                    if layers.layer(bg) && self.layer_bpp(bg) != 0 {
+                        pixels[bg] = self.bg_pixel(bg, x, y);
+                    }
+                }
+            }
+        }
+
+        for &(bg, prio) in self.layer_order() {
+            match pixels[bg] {
+                Some((color, p)) if p == prio => return Some(color),
+                _ => { }
+            }
+        }
+
+        None
+    }
+
+    fn layer_order(&self) -> &'static [(usize, bool)] {
+        match self.BGMODE.mode {
+            BGMODES::Mode0 => &MODE0_ORDER,
+            BGMODES::Mode1 => match self.BGMODE.bg3_priority {
+                true => &MODE1_BG3_ORDER,
+                false => &MODE1_ORDER,
+            },
+            BGMODES::Mode6 => &MODE6_ORDER,
+            BGMODES::Mode7 => &MODE7_EXTBG_ORDER,
+            _ => &MODE2_ORDER,
+        }
+    }
+
+    // Bits per pixel of a BG in the current mode, 0 if the
+    // BG doesn't exist in this mode
+    fn layer_bpp(&self, bg: usize) -> u16 {
+        match (self.BGMODE.mode, bg) {
+            (BGMODES::Mode0, _) => 2,
+            (BGMODES::Mode1, 0) | (BGMODES::Mode1, 1) => 4,
+            (BGMODES::Mode1, 2) => 2,
+            (BGMODES::Mode2, 0) | (BGMODES::Mode2, 1) => 4,
+            (BGMODES::Mode3, 0) => 8,
+            (BGMODES::Mode3, 1) => 4,
+            (BGMODES::Mode4, 0) => 8,
+            (BGMODES::Mode4, 1) => 2,
+            (BGMODES::Mode5, 0) => 4,
+            (BGMODES::Mode5, 1) => 2,
+            (BGMODES::Mode6, 0) => 4,
+            _ => 0,
+        }
+    }
+
+    fn offset_per_tile(&self) -> bool {
+        match self.BGMODE.mode {
+            BGMODES::Mode2 | BGMODES::Mode4 | BGMODES::Mode6 => true,
+            _ => false,
+        }
+    }
+
+    fn tile_size(&self, bg: usize) -> (u16, u16) {
+        let large = match bg {
+            0 => self.BGMODE.bg_sizes.0,
+            1 => self.BGMODE.bg_sizes.1,
+            2 => self.BGMODE.bg_sizes.2,
+            _ => self.BGMODE.bg_sizes.3,
+        } == CHARSIZE::S16;
+
+        match (self.BGMODE.mode, large) {
+            // Hi-res modes always fetch 16 pixel wide tiles
+            (BGMODES::Mode5, false) | (BGMODES::Mode6, false) => (16, 8),
+            (_, true) => (16, 16),
+            (_, false) => (8, 8),
+        }
+    }
+
+    fn char_base(&self, bg: usize) -> u16 {
+        match bg {
+            0 => self.BG12NBA.0,
+            1 => self.BG12NBA.1,
+            2 => self.BG34NBA.0,
+            _ => self.BG34NBA.1,
+        }
+    }
+
+    // Fetch the tilemap entry covering the (already scrolled) pixel
+    fn tilemap_entry(&self, bg: usize, x: u16, y: u16) -> u16 {
+        let sc = self.BGXSC[bg];
+        let (tile_w, tile_h) = self.tile_size(bg);
+
+        let tx = (x / tile_w) & 0x3F;
+        let ty = (y / tile_h) & 0x3F;
+
+        let mut offset = ((ty & 0x1F) << 5) + (tx & 0x1F);
+
+        match sc.size {
+            BGSIZE::S32x32 => { }
+            BGSIZE::S64x32 => if tx >= 32 { offset += 0x400 },
+            BGSIZE::S32x64 => if ty >= 32 { offset += 0x400 },
+            BGSIZE::S64x64 => {
+                if tx >= 32 { offset += 0x400 }
+                if ty >= 32 { offset += 0x800 }
+            }
+        }
+
+        vram(sc.addr.wrapping_add(offset))
+    }
+
+    // Returns the color and priority bit of a BG pixel,
+    // None if it's transparent
+    fn bg_pixel(&self, bg: usize, x: u16, y: u16) -> Option<(u16, bool)> {
+        let (x, y) = self.mosaic(bg, x, y);
+
+        // Hi-res scrolls in 512 pixel units and interlace
+        // Fetches alternating lines for each field
+        let hires = self.hires();
+        let y = match hires && self.SETINI.interlace {
+            true => (y << 1) | self.timing.field as u16,
+            false => y,
+        };
+        let scroll = match hires {
+            true => self.BGHOFS[bg] << 1,
+            false => self.BGHOFS[bg],
+        };
+
+        let mut hofs = x + scroll;
+        let mut vofs = y + self.BGVOFS[bg];
+
+        // Offset-per-tile, BG3's tilemap replaces BG1/BG2 scroll per column
+        if self.offset_per_tile() && bg < 2 {
+            let valid = 0x2000 << bg;
+            let column = x + (scroll & 7);
+
+            // The first column can't be offset
+            if column >= 8 {
+                let opt_x = (column - 8) + (self.BGHOFS[2] & !7);
+                let hlookup = self.tilemap_entry(2, opt_x, self.BGVOFS[2]);
+
+                if self.BGMODE.mode == BGMODES::Mode4 {
+                    if hlookup & valid != 0 {
+                        match hlookup & 0x8000 {
+                            0 => hofs = column.wrapping_add(hlookup & !7),
+                            _ => vofs = y.wrapping_add(hlookup),
+                        }
+                    }
+                } else {
+                    let vlookup = self.tilemap_entry(2, opt_x, self.BGVOFS[2] + 8);
+
+                    if hlookup & valid != 0 {
+                        hofs = column.wrapping_add(hlookup & !7);
+                    }
+                    if vlookup & valid != 0 {
+                        vofs = y.wrapping_add(vlookup);
+                    }
+                }
+            }
+        }
+
+        let entry = self.tilemap_entry(bg, hofs, vofs);
+        let bpp = self.layer_bpp(bg);
+        let (tile_w, tile_h) = self.tile_size(bg);
+
+        let mut px = hofs & (tile_w - 1);
+        let mut py = vofs & (tile_h - 1);
+        if entry & 0x4000 != 0 { px = tile_w - 1 - px; }
+        if entry & 0x8000 != 0 { py = tile_h - 1 - py; }
+
+        // 16x16 tiles are made of 4 neighbouring 8x8 characters
+        let tile = (entry & 0x3FF) + (px >> 3) + ((py >> 3) << 4);
+        let addr = self.char_base(bg).wrapping_add((tile & 0x3FF) * bpp * 4).wrapping_add(py & 7);
+        let color = tile_pixel(addr, bpp, px & 7);
+
+        if color == 0 {
+            return None;
+        }
+
+        let palette = ((entry >> 10) & 7) as u8;
+        let prio = entry & 0x2000 != 0;
+
+        let color = match bpp {
+            2 => match self.BGMODE.mode {
+                BGMODES::Mode0 => cgram_color((bg as u8) * 32 + palette * 4 + color),
+                _ => cgram_color(palette * 4 + color),
+            },
+            4 => cgram_color(palette * 16 + color),
+            _ => match self.direct_color() {
+                true => direct_color(palette, color),
+                false => cgram_color(color),
+            },
+        };
+
+        Some((color, prio))
+    }
+
+    // With EXTBG, BG2 shows the Mode 7 plane again using bit 7
+    // Of every pixel as its priority
+    fn mode7_pixel(&self, bg: usize, x: u16, y: u16) -> Option<(u16, bool)> {
+        let (x, y) = self.mosaic(bg, x, y);
+
+        let a = self.M7A as i16 as i32;
+        let b = self.M7B as i16 as i32;
+        let c = self.M7C as i16 as i32;
+        let d = self.M7D as i16 as i32;
+
+        let cx = sign13(self.M7X);
+        let cy = sign13(self.M7Y);
+        let hofs = sign13(self.M7HOFS);
+        let vofs = sign13(self.M7VOFS);
+
+        let sx = if self.M7SEL.hflip { 255 - x as i32 } else { x as i32 };
+        let sy = if self.M7SEL.vflip { 255 - y as i32 } else { y as i32 };
+
+        let ox = ((a * clip13(hofs - cx)) & !63) + ((b * clip13(vofs - cy)) & !63) + ((b * sy) & !63) + (cx << 8);
+        let oy = ((c * clip13(hofs - cx)) & !63) + ((d * clip13(vofs - cy)) & !63) + ((d * sy) & !63) + (cy << 8);
+
+        let px = (ox + a * sx) >> 8;
+        let py = (oy + c * sx) >> 8;
+
+        let outside = px < 0 || px > 1023 || py < 0 || py > 1023;
+
+        let tile = match (outside, self.M7SEL.over) {
+            (true, M7OVER::Transparent) => return None,
+            (true, M7OVER::Tile0) => 0,
+            _ => vram(((((py >> 3) & 127) << 7) | ((px >> 3) & 127)) as u16) & 0xFF,
+        };
+
+        let color = (vram((tile << 6) | (((py & 7) << 3) | (px & 7)) as u16) >> 8) as u8;
+
+        match (bg, color & 0x7F, color) {
+            (0, _, 0) => None,
+            (0, _, _) => match self.direct_color() {
+                true => Some((direct_color(0, color), false)),
+                false => Some((cgram_color(color), false)),
+            },
+            (_, 0, _) => None,
+            _ => Some((cgram_color(color & 0x7F), color & 0x80 == 0x80)),
+        }
+    }
+}
+
+#[inline]
+fn vram(addr: u16) -> u16 {
+    unsafe { Scrn::VRAM[(addr & 0x7FFF) as usize] }
+}
+
+#[inline]
+fn cgram_color(index: u8) -> u16 {
+    unsafe { Scrn::CGRAM[index as usize] }
+}
+
+// The pixel is BBGGGRRR and the palette bits of the tilemap
+// Entry supply one more bit per channel, giving 0BBb00GGGg0RRRr0
+#[inline]
+fn direct_color(palette: u8, color: u8) -> u16 {
+    let palette = palette as u16;
+    let color = color as u16;
+
+    ((color << 2) & 0x001C) | ((palette << 1) & 0x0002) |
+    ((color << 4) & 0x0380) | ((palette << 5) & 0x0040) |
+    ((color << 7) & 0x6000) | ((palette << 10) & 0x1000)
+}
+
+// Decode one pixel of a planar character row
+fn tile_pixel(addr: u16, bpp: u16, x: u16) -> u8 {
+    let mut color = 0u8;
+    let bit = 7 - x;
+
+    for plane in 0..(bpp / 2) {
+        let word = vram(addr.wrapping_add(plane * 8));
+        color |= (((word >> bit) & 1) as u8) << (plane * 2);
+        color |= (((word >> (bit + 8)) & 1) as u8) << (plane * 2 + 1);
+    }
+
+    color
+}
+
+#[inline]
+fn sign13(val: u16) -> i32 {
+    ((val << 3) as i16 >> 3) as i32
+}
+
+#[inline]
+fn clip13(val: i32) -> i32 {
+    if val & 0x2000 != 0 { val | !1023 } else { val & 1023 }
+}
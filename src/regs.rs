@@ -8,6 +8,27 @@ pub enum DMATransferMode {
     RWRW,   // 2 registers write twice alternate
 }
 
+// B-bus register offsets of one transfer unit
+static PATTERN_RW: [u8; 1] = [0];
+static PATTERN_RRW: [u8; 2] = [0, 1];
+static PATTERN_RWW: [u8; 2] = [0, 0];
+static PATTERN_RRWW: [u8; 4] = [0, 0, 1, 1];
+static PATTERN_RRRRW: [u8; 4] = [0, 1, 2, 3];
+static PATTERN_RWRW: [u8; 4] = [0, 1, 0, 1];
+
+impl DMATransferMode {
+    pub fn pattern(&self) -> &'static [u8] {
+        match *self {
+            DMATransferMode::RW => &PATTERN_RW,
+            DMATransferMode::RRW => &PATTERN_RRW,
+            DMATransferMode::RWW => &PATTERN_RWW,
+            DMATransferMode::RRWW => &PATTERN_RRWW,
+            DMATransferMode::RRRRW => &PATTERN_RRRRW,
+            DMATransferMode::RWRW => &PATTERN_RWRW,
+        }
+    }
+}
+
 impl Default for DMATransferMode {
     fn default() -> DMATransferMode {
         DMATransferMode::RW
@@ -200,6 +221,28 @@ impl Default for VREMAP {
     }
 }
 
+impl VREMAP {
+    // Translate a VMADD word address into the VRAM address
+    pub fn apply(&self, addr: u16) -> u16 {
+        match *self {
+            VREMAP::None => addr,
+            VREMAP::First => (addr & 0xFF00) | ((addr & 0x001F) << 3) | ((addr >> 5) & 7),
+            VREMAP::Second => (addr & 0xFE00) | ((addr & 0x003F) << 3) | ((addr >> 6) & 7),
+            VREMAP::Third => (addr & 0xFC00) | ((addr & 0x007F) << 3) | ((addr >> 7) & 7),
+        }
+    }
+}
+
+impl VINCAM {
+    pub fn amount(&self) -> u16 {
+        match *self {
+            VINCAM::One => 1,
+            VINCAM::ThirtyTwo => 32,
+            VINCAM::OneTwentyEight => 128,
+        }
+    }
+}
+
 impl From<u8> for VREMAP {
     fn from(val: u8) -> VREMAP {
         match val & 0b00001100 {
@@ -233,7 +276,7 @@ impl From<u8> for VMAIN {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BGMODES {
     Mode0,
     Mode1,
@@ -267,7 +310,7 @@ impl From<u8> for BGMODES {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CHARSIZE {
     S8,
     S16,
@@ -289,7 +332,7 @@ impl From<u8> for CHARSIZE {
 }
 
 #[derive(Debug, Clone, Copy, Default)]
-pub struct BGCHAR(CHARSIZE, CHARSIZE, CHARSIZE, CHARSIZE);
+pub struct BGCHAR(pub CHARSIZE, pub CHARSIZE, pub CHARSIZE, pub CHARSIZE);
 
 impl From<u8> for BGCHAR {
     fn from(val: u8) -> BGCHAR {
@@ -303,23 +346,26 @@ impl From<u8> for BGCHAR {
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct BGMODE {
-    bg_sizes: BGCHAR,
-    mode: BGMODES,
+    pub bg_sizes: BGCHAR,
+    pub mode: BGMODES,
+    pub bg3_priority: bool,
 }
 
 impl From<u8> for BGMODE {
     fn from(val: u8) -> Self {
         let mode = BGMODES::from(val);
         let size = BGCHAR::from(val);
+        let prio = (val & 0b00001000) == 0b00001000;
 
         Self {
             bg_sizes: size,
             mode: mode,
+            bg3_priority: prio,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BGSIZE {
     S32x32,
     S64x32,
@@ -347,8 +393,8 @@ impl From<u8> for BGSIZE {
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct BGXSC {
-    addr: u16,
-    size: BGSIZE,
+    pub addr: u16,
+    pub size: BGSIZE,
 }
 
 impl From<u8> for BGXSC {
@@ -364,7 +410,7 @@ impl From<u8> for BGXSC {
 }
 
 #[derive(Debug, Clone, Copy, Default)]
-pub struct BGNBA(u16, u16);
+pub struct BGNBA(pub u16, pub u16);
 
 impl From<u8> for BGNBA {
     fn from(val: u8) -> BGNBA {
@@ -380,11 +426,11 @@ impl From<u8> for BGNBA {
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct SCRDES {
-    bg1: bool,
-    bg2: bool,
-    bg3: bool,
-    bg4: bool,
-    obj: bool,
+    pub bg1: bool,
+    pub bg2: bool,
+    pub bg3: bool,
+    pub bg4: bool,
+    pub obj: bool,
 }
 
 impl From<u8> for SCRDES {
@@ -404,3 +450,129 @@ impl From<u8> for SCRDES {
         }
     }
 }
+
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MOSAIC {
+    pub size: u16,
+    pub bg1: bool,
+    pub bg2: bool,
+    pub bg3: bool,
+    pub bg4: bool,
+}
+
+impl From<u8> for MOSAIC {
+    fn from(val: u8) -> MOSAIC {
+        let size = ((val >> 4) as u16) + 1;
+        let bg1 = (val & 0b0001) == 0b0001;
+        let bg2 = (val & 0b0010) == 0b0010;
+        let bg3 = (val & 0b0100) == 0b0100;
+        let bg4 = (val & 0b1000) == 0b1000;
+
+        Self {
+            size: size,
+            bg1: bg1,
+            bg2: bg2,
+            bg3: bg3,
+            bg4: bg4,
+        }
+    }
+}
+
+impl MOSAIC {
+    pub fn layer(&self, bg: usize) -> bool {
+        match bg {
+            0 => self.bg1,
+            1 => self.bg2,
+            2 => self.bg3,
+            3 => self.bg4,
+            _ => false,
+        }
+    }
+}
+
+impl SCRDES {
+    pub fn layer(&self, bg: usize) -> bool {
+        match bg {
+            0 => self.bg1,
+            1 => self.bg2,
+            2 => self.bg3,
+            3 => self.bg4,
+            _ => self.obj,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum M7OVER {
+    Wrap,       // Playing field repeats
+    Transparent,// Outside the playing field is transparent
+    Tile0,      // Outside the playing field is filled with tile 0
+}
+
+impl Default for M7OVER {
+    fn default() -> M7OVER {
+        M7OVER::Wrap
+    }
+}
+
+impl From<u8> for M7OVER {
+    fn from(val: u8) -> M7OVER {
+        match val & 0b11000000 {
+            0b10000000 => M7OVER::Transparent,
+            0b11000000 => M7OVER::Tile0,
+            _ => M7OVER::Wrap,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct M7SEL {
+    pub hflip: bool,
+    pub vflip: bool,
+    pub over: M7OVER,
+}
+
+impl From<u8> for M7SEL {
+    fn from(val: u8) -> M7SEL {
+        let hflip = (val & 0b01) == 0b01;
+        let vflip = (val & 0b10) == 0b10;
+        let over = M7OVER::from(val);
+
+        Self {
+            hflip: hflip,
+            vflip: vflip,
+            over: over,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SETINI {
+    pub interlace: bool,
+    pub obj_interlace: bool,
+    pub overscan: bool,
+    pub pseudo_hires: bool,
+    pub extbg: bool,
+    pub external_sync: bool,
+}
+
+impl From<u8> for SETINI {
+    fn from(val: u8) -> SETINI {
+        let interlace = (val & 0b00000001) == 0b00000001;
+        let obj_interlace = (val & 0b00000010) == 0b00000010;
+        let overscan = (val & 0b00000100) == 0b00000100;
+        let pseudo_hires = (val & 0b00001000) == 0b00001000;
+        let extbg = (val & 0b01000000) == 0b01000000;
+        let external_sync = (val & 0b10000000) == 0b10000000;
+
+        Self {
+            interlace: interlace,
+            obj_interlace: obj_interlace,
+            overscan: overscan,
+            pseudo_hires: pseudo_hires,
+            extbg: extbg,
+            external_sync: external_sync,
+        }
+    }
+}
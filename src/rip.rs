@@ -0,0 +1,337 @@
+use apu::Apu;
+use audio::{write_u16, write_u32, DSP_RATE};
+use dsp::*;
+use spc::Spc;
+
+use std::fs::{self, File};
+use std::io::{self, Write, BufWriter};
+
+const BLOCK_SIZE: usize = 9;
+
+// Samples nobody played only count when every block has a
+// Sane shift, anything over 12 is most likely not a sample
+const MAX_SHIFT: u8 = 12;
+
+// A sample pulled out of ARAM
+pub struct Sample {
+    pub srcn: u8,
+    // Later directory entries pointing at the same sample
+    pub aliases: Vec<u8>,
+    pub start: u16,
+    pub loop_addr: u16,
+    pub looped: bool,
+    // Where the loop starts in `data`, when it lands on a block
+    pub loop_start: Option<usize>,
+    pub data: Vec<i16>,
+    pub used: bool,
+}
+
+// Register settings a voice was heard playing with
+#[derive(Clone, PartialEq)]
+pub struct VoiceSettings {
+    pub srcn: u8,
+    pub pitch: u16,
+    pub adsr1: u8,
+    pub adsr2: u8,
+    pub gain: u8,
+    pub voll: i8,
+    pub volr: i8,
+}
+
+#[derive(Clone)]
+pub struct VoiceUse {
+    pub settings: VoiceSettings,
+    // How many 32 kHz samples it was heard for
+    pub samples: u32,
+}
+
+pub struct Rip {
+    pub dir: u16,
+    pub samples: Vec<Sample>,
+    pub voices: Vec<Vec<VoiceUse>>,
+}
+
+impl Rip {
+    // Plays the snapshot for `seconds` noting what every voice
+    // Does, then pulls out the samples in the directory
+    pub fn run(spc: &Spc, seconds: u32) -> Rip {
+        let mut apu = Apu::new();
+        spc.apply(&mut apu);
+
+        let mut voices = vec![Vec::<VoiceUse>::new(); 8];
+        for _ in 0..seconds * DSP_RATE {
+            apu.run_samples(2);
+            apu.samples.clear();
+
+            for v in 0..8 {
+                if apu.dsp.voices[v].env == 0 {
+                    continue;
+                }
+
+                let settings = voice_settings(&apu.dsp.regs, v);
+                let seen = &mut voices[v];
+                match seen.iter().position(|u| u.settings == settings) {
+                    Some(i) => seen[i].samples += 1,
+                    None => seen.push(VoiceUse {
+                        settings: settings,
+                        samples: 1,
+                    }),
+                }
+            }
+        }
+
+        let dir = (apu.dsp.regs[R_DIR] as u16) << 8;
+        let mut samples: Vec<Sample> = Vec::new();
+        for srcn in 0..256 {
+            let srcn = srcn as u8;
+            let used = voices.iter().any(|seen| seen.iter().any(|u| u.settings.srcn == srcn));
+
+            let entry = dir as usize + srcn as usize * 4;
+            if entry + 4 > apu.aram.len() {
+                break;
+            }
+            let start = read_u16(&apu.aram, entry);
+            let loop_addr = read_u16(&apu.aram, entry + 2);
+
+            // Several entries often share one sample, it's used if
+            // Any of them is
+            if let Some(sample) = samples.iter_mut().find(|s| s.start == start && s.loop_addr == loop_addr) {
+                sample.aliases.push(srcn);
+                sample.used |= used;
+                continue;
+            }
+
+            if let Some(sample) = decode(&apu.aram, srcn, start, loop_addr, used) {
+                samples.push(sample);
+            }
+        }
+
+        Rip {
+            dir: dir,
+            samples: samples,
+            voices: voices,
+        }
+    }
+
+    pub fn save(&self, path: &str, game: &str) -> io::Result<()> {
+        fs::create_dir_all(path)?;
+
+        for sample in &self.samples {
+            let name = format!("{}/{}", path, sample_name(sample.srcn));
+            write_wav(&name, sample)?;
+        }
+
+        let mut json = BufWriter::new(File::create(format!("{}/manifest.json", path))?);
+        self.write_manifest(&mut json, game)?;
+        json.flush()
+    }
+
+    // The sample a directory entry plays, shared ones are
+    // Saved under the first entry pointing at them
+    pub fn sample(&self, srcn: u8) -> Option<&Sample> {
+        self.samples.iter().find(|s| s.srcn == srcn || s.aliases.contains(&srcn))
+    }
+
+    // Written by hand, it is only a couple of lists
+    fn write_manifest<W: Write>(&self, w: &mut W, game: &str) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"game\": \"{}\",", escape(game))?;
+        writeln!(w, "  \"dir\": {},", self.dir)?;
+
+        writeln!(w, "  \"samples\": [")?;
+        for (i, sample) in self.samples.iter().enumerate() {
+            let loop_start = match sample.loop_start {
+                Some(start) => format!("{}", start),
+                None => String::from("null"),
+            };
+            write!(w, "    {{ \"srcn\": {}, \"file\": \"{}\", \"start\": {}, \"loop\": {}, ",
+                sample.srcn, sample_name(sample.srcn), sample.start, sample.loop_addr)?;
+            let aliases: Vec<String> = sample.aliases.iter().map(|a| format!("{}", a)).collect();
+            write!(w, "\"looped\": {}, \"loop_start\": {}, \"length\": {}, \"used\": {}, \"aliases\": [{}] }}",
+                sample.looped, loop_start, sample.data.len(), sample.used, aliases.join(", "))?;
+            writeln!(w, "{}", if i + 1 < self.samples.len() { "," } else { "" })?;
+        }
+        writeln!(w, "  ],")?;
+
+        writeln!(w, "  \"voices\": [")?;
+        for (v, seen) in self.voices.iter().enumerate() {
+            writeln!(w, "    {{ \"voice\": {}, \"settings\": [", v)?;
+            for (i, u) in seen.iter().enumerate() {
+                let s = &u.settings;
+                let file = match self.sample(s.srcn) {
+                    Some(sample) => format!("\"{}\"", sample_name(sample.srcn)),
+                    None => String::from("null"),
+                };
+                write!(w, "      {{ \"srcn\": {}, \"file\": {}, \"pitch\": {}, \"rate\": {}, ",
+                    s.srcn, file, s.pitch, s.pitch as u32 * DSP_RATE / 0x1000)?;
+                write!(w, "\"voll\": {}, \"volr\": {}, \"adsr1\": {}, \"adsr2\": {}, \"gain\": {}, ",
+                    s.voll, s.volr, s.adsr1, s.adsr2, s.gain)?;
+                write!(w, "\"envelope\": {}, \"seconds\": {:.3} }}",
+                    envelope(s), u.samples as f64 / DSP_RATE as f64)?;
+                writeln!(w, "{}", if i + 1 < seen.len() { "," } else { "" })?;
+            }
+            writeln!(w, "    ] }}{}", if v + 1 < self.voices.len() { "," } else { "" })?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+}
+
+fn voice_settings(regs: &[u8], v: usize) -> VoiceSettings {
+    let reg = |r: usize| regs[(v << 4) | r];
+    VoiceSettings {
+        srcn: reg(V_SRCN),
+        pitch: ((reg(V_PITCHL) as u16) | ((reg(V_PITCHH) as u16) << 8)) & 0x3FFF,
+        adsr1: reg(V_ADSR1),
+        adsr2: reg(V_ADSR2),
+        gain: reg(V_GAIN),
+        voll: reg(V_VOLL) as i8,
+        volr: reg(V_VOLR) as i8,
+    }
+}
+
+// ADSR1 bit 7 picks ADSR over GAIN
+fn envelope(s: &VoiceSettings) -> String {
+    if s.adsr1 & 0x80 == 0x80 {
+        format!("{{ \"mode\": \"adsr\", \"attack\": {}, \"decay\": {}, \"sustain_level\": {}, \"sustain_rate\": {} }}",
+            s.adsr1 & 0xF, (s.adsr1 >> 4) & 7, s.adsr2 >> 5, s.adsr2 & 0x1F)
+    } else if s.gain & 0x80 == 0 {
+        format!("{{ \"mode\": \"direct\", \"level\": {} }}", s.gain & 0x7F)
+    } else {
+        let mode = match (s.gain >> 5) & 3 {
+            0 => "decrease",
+            1 => "exponential_decrease",
+            2 => "increase",
+            _ => "bent_increase",
+        };
+        format!("{{ \"mode\": \"{}\", \"rate\": {} }}", mode, s.gain & 0x1F)
+    }
+}
+
+// Runs the BRR blocks from `start` up to the one with the end flag,
+// Gives up on anything running off the end of ARAM
+fn decode(aram: &[u8], srcn: u8, start: u16, loop_addr: u16, used: bool) -> Option<Sample> {
+    let mut data = Vec::new();
+    let mut addr = start as usize;
+    let (mut p1, mut p2) = (0i32, 0i32);
+
+    loop {
+        if addr + BLOCK_SIZE > aram.len() {
+            return None;
+        }
+
+        let header = aram[addr];
+        if !used && header >> 4 > MAX_SHIFT {
+            return None;
+        }
+
+        for i in 0..8 {
+            let byte = aram[addr + 1 + i];
+            for &nybble in &[byte >> 4, byte & 0xF] {
+                let s = ((nybble << 4) as i8 >> 4) as i32;
+                let out = brr_sample(header, s, p1, p2);
+                p2 = p1;
+                p1 = out as i32;
+                data.push(out);
+            }
+        }
+
+        addr += BLOCK_SIZE;
+        if header & 1 == 1 {
+            let looped = header & 2 == 2;
+            let offset = loop_addr as usize;
+            let loop_start = if looped && offset >= start as usize && offset < addr
+                && (offset - start as usize) % BLOCK_SIZE == 0 {
+                Some((offset - start as usize) / BLOCK_SIZE * 16)
+            } else {
+                None
+            };
+
+            return Some(Sample {
+                srcn: srcn,
+                aliases: Vec::new(),
+                start: start,
+                loop_addr: loop_addr,
+                looped: looped,
+                loop_start: loop_start,
+                data: data,
+                used: used,
+            });
+        }
+    }
+}
+
+fn sample_name(srcn: u8) -> String {
+    format!("sample_{:02X}.wav", srcn)
+}
+
+// Mono 16 bit at the rate a pitch of $1000 plays, with a smpl
+// Chunk so samplers pick up the loop
+fn write_wav(path: &str, sample: &Sample) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+
+    let bytes = sample.data.len() as u32 * 2;
+    let loops = if sample.loop_start.is_some() { 1 } else { 0 };
+    let smpl = 36 + 24 * loops;
+
+    w.write_all(b"RIFF")?;
+    write_u32(&mut w, 4 + (8 + 16) + (8 + bytes) + (8 + smpl))?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    write_u32(&mut w, 16)?;
+    write_u16(&mut w, 1)?;
+    write_u16(&mut w, 1)?;
+    write_u32(&mut w, DSP_RATE)?;
+    write_u32(&mut w, DSP_RATE * 2)?;
+    write_u16(&mut w, 2)?;
+    write_u16(&mut w, 16)?;
+
+    w.write_all(b"data")?;
+    write_u32(&mut w, bytes)?;
+    for &s in &sample.data {
+        write_u16(&mut w, s as u16)?;
+    }
+
+    w.write_all(b"smpl")?;
+    write_u32(&mut w, smpl)?;
+    // Manufacturer, product, period in ns, unity note 60
+    write_u32(&mut w, 0)?;
+    write_u32(&mut w, 0)?;
+    write_u32(&mut w, 1_000_000_000 / DSP_RATE)?;
+    write_u32(&mut w, 60)?;
+    // Pitch fraction, SMPTE format and offset
+    write_u32(&mut w, 0)?;
+    write_u32(&mut w, 0)?;
+    write_u32(&mut w, 0)?;
+    write_u32(&mut w, loops)?;
+    write_u32(&mut w, 0)?;
+    if let Some(start) = sample.loop_start {
+        // Forward loop up to and including the last sample
+        write_u32(&mut w, 0)?;
+        write_u32(&mut w, 0)?;
+        write_u32(&mut w, start as u32)?;
+        write_u32(&mut w, sample.data.len() as u32 - 1)?;
+        write_u32(&mut w, 0)?;
+        write_u32(&mut w, 0)?;
+    }
+
+    w.flush()
+}
+
+fn read_u16(aram: &[u8], addr: usize) -> u16 {
+    (aram[addr] as u16) | ((aram[addr + 1] as u16) << 8)
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
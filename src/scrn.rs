@@ -1,20 +1,144 @@
-use std::sync::atomic::{AtomicU8};
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Mutex, Arc};
 use std::cell::{Cell, UnsafeCell};
 use std::time::Duration;
 use std::thread;
+use std::cmp;
 
 use clock_ticks;
 
-use minifb::{Key, Window, WindowOptions, Scale};
+use minifb::{Key, KeyRepeat, Window, WindowOptions, Scale, MouseMode, MouseButton};
+
+use dsp::EnvelopeMode;
+
+use input::*;
 
 pub mod Scrn {
+    use std::sync::atomic::{AtomicIsize, ATOMIC_ISIZE_INIT, AtomicU8, ATOMIC_U8_INIT, AtomicBool, ATOMIC_BOOL_INIT};
+    use dsp::{VoiceState, VOICE_STATE_INIT};
+
     pub static mut SCREEN: Option<super::Screen> = None;
-    pub static mut PALETTE_INDEX: u16 = 0u16;
-    pub static mut PALETTE: [u8; 1024] = [0u8; 1024];
+    pub static mut CGRAM_ADDR: u16 = 0u16;
+    pub static mut CGRAM: [u16; 256] = [0u16; 256];
     pub static mut RUNNING: bool = false;
     pub static mut VRAM: [u16; 0x10000] = [0u16; 0x10000];
     pub static mut VRAM_ADDR: u16 = 0u16;
+    pub static mut VRAM_LATCH: u16 = 0u16;
+    // Rows are always 512 pixels apart, low-res lines store
+    // Every pixel twice so lines of both kinds can be mixed
+    pub static mut FRAME: [u32; 512 * 478] = [0u32; 512 * 478];
+    pub static mut FRAME_WIDTH: usize = 256;
+    pub static mut FRAME_HEIGHT: usize = 224;
+    pub static mut FRAME_COUNT: u64 = 0u64;
+    // Buttons held on every pad, the window feeds player 1
+    pub static mut JOYPAD: [u16; 8] = [0u16; 8];
+    // Mouse motion not yet sent to the SNES, and its buttons
+    pub static MOUSE_X: AtomicIsize = ATOMIC_ISIZE_INIT;
+    pub static MOUSE_Y: AtomicIsize = ATOMIC_ISIZE_INIT;
+    pub static mut MOUSE_BUTTONS: u8 = 0u8;
+    // Where the light guns point in SNES pixels, and their buttons
+    pub static mut GUN_X: [isize; 2] = [-1isize; 2];
+    pub static mut GUN_Y: [isize; 2] = [-1isize; 2];
+    pub static mut GUN_BUTTONS: [u8; 2] = [0u8; 2];
+    // DSP voices muted from the window or the debugger, bit n is
+    // Voice n, and what the voices were doing at the last frame
+    pub static VOICE_MUTE: AtomicU8 = ATOMIC_U8_INIT;
+    pub static ECHO_MUTE: AtomicBool = ATOMIC_BOOL_INIT;
+    pub static mut VOICES: [VoiceState; 8] = [VOICE_STATE_INIT; 8];
+}
+
+// Keyboard layout for player 1
+static KEYMAP: [(Key, Buttons); 12] = [
+    (Key::Z, BUTTON_B),
+    (Key::A, BUTTON_Y),
+    (Key::RightShift, BUTTON_SELECT),
+    (Key::Enter, BUTTON_START),
+    (Key::Up, BUTTON_UP),
+    (Key::Down, BUTTON_DOWN),
+    (Key::Left, BUTTON_LEFT),
+    (Key::Right, BUTTON_RIGHT),
+    (Key::X, BUTTON_A),
+    (Key::S, BUTTON_X),
+    (Key::Q, BUTTON_L),
+    (Key::W, BUTTON_R),
+];
+
+// Number keys toggle the mute of each DSP voice, with left
+// Shift they solo it instead, right shift is SELECT
+static VOICE_KEYS: [Key; 8] = [
+    Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8,
+];
+
+pub const FRAME_STRIDE: usize = 512;
+
+pub fn toggle_mute(voice: usize) {
+    Scrn::VOICE_MUTE.fetch_xor(1 << voice, Ordering::SeqCst);
+}
+
+// Soloing the only unmuted voice unmutes everything again
+pub fn toggle_solo(voice: usize) {
+    let solo = !(1u8 << voice);
+    let mute = Scrn::VOICE_MUTE.load(Ordering::SeqCst);
+    Scrn::VOICE_MUTE.store(if mute == solo { 0 } else { solo }, Ordering::SeqCst);
+}
+
+pub fn toggle_echo_mute() {
+    let mute = Scrn::ECHO_MUTE.load(Ordering::SeqCst);
+    Scrn::ECHO_MUTE.store(!mute, Ordering::SeqCst);
+}
+
+// One column per voice in the corner of the window. The envelope
+// Bar is green while keyed and red once released, grey when muted,
+// The blue bar is the pitch and the squares below are SRCN in binary.
+fn draw_voices(buff: &mut [u32], width: usize) {
+    let mute = Scrn::VOICE_MUTE.load(Ordering::SeqCst);
+    let echo_mute = Scrn::ECHO_MUTE.load(Ordering::SeqCst);
+
+    for v in 0..8 {
+        let state = unsafe { Scrn::VOICES[v] };
+        let left = 8 + v * 20;
+
+        let env_color = if mute & (1 << v) != 0 {
+            0x606060
+        } else if state.mode == EnvelopeMode::Release {
+            0xC03030
+        } else {
+            0x30C030
+        };
+        let echo_color = match (state.echo, echo_mute) {
+            (false, _) => 0x202020,
+            (true, false) => 0x30C0C0,
+            (true, true) => 0x606060,
+        };
+        let pitch = (state.pitch >> 7) as usize;
+
+        for y in 0..128 {
+            let height = 127 - y;
+            for x in 0..16 {
+                let color = match x {
+                    0...9 if height < state.env as usize => env_color,
+                    11...14 if height < pitch => 0x3060E0,
+                    _ => 0x101010,
+                };
+                buff[(left + x) + (y + 8) * width] = color;
+            }
+        }
+
+        // Key state and echo across the top, SRCN underneath
+        for x in 0..16 {
+            buff[(left + x) + 2 * width] = if state.keyed { 0xFFFFFF } else { 0x404040 };
+            buff[(left + x) + 4 * width] = echo_color;
+        }
+        for bit in 0..8 {
+            let color = if state.srcn & (0x80 >> bit) != 0 { 0xFFFFFF } else { 0x404040 };
+            for y in 0..3 {
+                for x in 0..3 {
+                    buff[(left + (bit % 4) * 4 + x) + (140 + (bit / 4) * 4 + y) * width] = color;
+                }
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -51,6 +175,17 @@ pub fn get_color(color: u16) -> u32 {
     (((((((color & 0b0111110000000000) >> 10) * 255) / 31) as u32) << 00) & 0x0000FF)
 }
 
+// Scale a color by the master brightness (0-15)
+#[inline]
+pub fn get_brightness(color: u32, brightness: u8) -> u32 {
+    let brightness = brightness as u32;
+    let r = (((color >> 16) & 0xFF) * brightness) / 15;
+    let g = (((color >> 08) & 0xFF) * brightness) / 15;
+    let b = (((color >> 00) & 0xFF) * brightness) / 15;
+
+    (r << 16) | (g << 8) | b
+}
+
 pub fn draw_loop<F>(rate: u64, mut callback: F) where 
     F: FnMut() -> State {
     let mut accumulator = 0;
@@ -91,26 +226,107 @@ impl Screen {
 
         let buffer = Arc::new(Mutex::new(vec![0u32; width * height]));
 
+        let mut last_frame = 0u64;
+        let mut last_mouse: Option<(f32, f32)> = None;
+        let mut show_voices = false;
+
         draw_loop(60, || {
             if window.is_open() && !window.is_key_down(Key::Escape) {
                 let buff = &mut buffer.lock().unwrap();
-                for i in buff.iter_mut() {
-                    *i = unsafe { get_color((Scrn::PALETTE[0] as u16) | ((Scrn::PALETTE[1] as u16) << 8)) };
-                }
 
-                unsafe {
-                    for y in 0..7 {
-                        let word = Scrn::VRAM[8 + y];
-                        for x in 0...7 {
-                            let col1 = (((word & 0xFF) >> x) as u8) & 0b1;
-                            let col1 = col1 as usize;
-                            buff[x + y * width] = get_color((Scrn::PALETTE[col1 * 2] as u16) | ((Scrn::PALETTE[col1 * 2 + 1] as u16) << 8));
+                // The PPU picks the resolution of every frame, scale
+                // It up to fit the window and center it vertically
+                let (frame_w, frame_h) = unsafe { (Scrn::FRAME_WIDTH, Scrn::FRAME_HEIGHT) };
+                let scale_x = cmp::max(width / frame_w, 1);
+                let scale_y = cmp::max(height / frame_h, 1);
+                let top = height.saturating_sub(frame_h * scale_y) / 2;
+
+                // Only present frames the emulation has finished
+                let frame = unsafe { Scrn::FRAME_COUNT };
+                if frame != last_frame {
+                    last_frame = frame;
+
+                    let step = FRAME_STRIDE / frame_w;
+
+                    for y in 0..height {
+                        if y < top || y >= top + frame_h * scale_y {
+                            for x in 0..width {
+                                buff[x + y * width] = 0u32;
+                            }
+                            continue;
+                        }
+
+                        let row = ((y - top) / scale_y) * FRAME_STRIDE;
+                        for x in 0..width {
+                            let col = cmp::min((x / scale_x) * step + step - 1, FRAME_STRIDE - 1);
+                            buff[x + y * width] = unsafe { Scrn::FRAME[row + col] };
                         }
                     }
+
+                    if show_voices && width >= 168 && height >= 148 {
+                        draw_voices(buff, width);
+                    }
                 }
 
                 window.update_with_buffer(&buff);
 
+                let mut buttons = Buttons::empty();
+                for &(key, button) in KEYMAP.iter() {
+                    if window.is_key_down(key) {
+                        buttons.insert(button);
+                    }
+                }
+                unsafe { Scrn::JOYPAD[0] = buttons.bits(); }
+
+                let shift = window.is_key_down(Key::LeftShift);
+                for (voice, &key) in VOICE_KEYS.iter().enumerate() {
+                    if window.is_key_pressed(key, KeyRepeat::No) {
+                        if shift { toggle_solo(voice) } else { toggle_mute(voice) }
+                    }
+                }
+                if window.is_key_pressed(Key::Key0, KeyRepeat::No) {
+                    toggle_echo_mute();
+                }
+                if window.is_key_pressed(Key::F1, KeyRepeat::No) {
+                    show_voices = !show_voices;
+                }
+
+                // The mouse moves in window pixels, scaled down the same
+                // Way the frame was scaled up
+                let mouse = window.get_mouse_pos(MouseMode::Pass);
+                if let (Some((x, y)), Some((last_x, last_y))) = (mouse, last_mouse) {
+                    Scrn::MOUSE_X.fetch_add(((x - last_x) / scale_x as f32) as isize, Ordering::SeqCst);
+                    Scrn::MOUSE_Y.fetch_add(((y - last_y) / scale_y as f32) as isize, Ordering::SeqCst);
+                }
+                last_mouse = mouse;
+
+                let mut mouse_buttons = 0u8;
+                if window.get_mouse_down(MouseButton::Left) { mouse_buttons |= MOUSE_LEFT; }
+                if window.get_mouse_down(MouseButton::Right) { mouse_buttons |= MOUSE_RIGHT; }
+                unsafe { Scrn::MOUSE_BUTTONS = mouse_buttons; }
+
+                // The first light gun follows the host cursor, mapped back
+                // Through the letterbox onto dots and lines. Hi-res and
+                // Interlaced frames have two pixels per dot or line
+                let mut gun_buttons = 0u8;
+                if window.get_mouse_down(MouseButton::Left) { gun_buttons |= GUN_TRIGGER; }
+                if window.get_mouse_down(MouseButton::Right) { gun_buttons |= GUN_CURSOR; }
+                if window.is_key_down(Key::T) { gun_buttons |= GUN_TURBO; }
+                if window.is_key_down(Key::P) { gun_buttons |= GUN_PAUSE; }
+                unsafe {
+                    let (gun_x, gun_y) = match mouse {
+                        Some((x, y)) if x >= 0.0 && y >= top as f32 => {
+                            let interlace = if frame_h > 240 { 2 } else { 1 };
+                            ((x as usize / scale_x / (frame_w / 256)) as isize,
+                             ((y as usize - top) / scale_y / interlace) as isize)
+                        }
+                        _ => (-1, -1),
+                    };
+                    Scrn::GUN_X[0] = gun_x;
+                    Scrn::GUN_Y[0] = gun_y;
+                    Scrn::GUN_BUTTONS[0] = gun_buttons;
+                }
+
                 State::Continue
             } else {
                 unsafe { Scrn::RUNNING = false; }
@@ -3,8 +3,14 @@ use inst::Instruction;
 use cpu::Ricoh5A22;
 use scrn::{Screen, Scrn};
 use mem::Memory;
+use timing::Event;
+use input::InputScript;
+use audio::{self, SharedSink};
+use coprocessor::Coprocessor;
+use superfx::SuperFx;
 
 use std::cell::RefMut;
+use std::sync::atomic::Ordering;
 
 #[derive(Clone)]
 pub struct SNES {
@@ -12,32 +18,169 @@ pub struct SNES {
     pub cpu: Ricoh5A22,
     pub mem: Memory,
     pub step: u64,
+    // Master clocks and frames since power on
+    pub clock: u64,
+    pub frames: u64,
+    pub script: Option<InputScript>,
+    pub audio: SharedSink,
 }
 
 impl SNES {
     pub fn new(rom: Vec<u8>) -> SNES {
-        let cart = SnesCart::new(rom);
+        let cart = SnesCart::new(rom.clone());
         let cpu = Default::default();
-        let mem = Memory::new(cart.clone());
+        let mut mem = Memory::new(cart.clone());
 
         let hdr = SnesHeader::from(cart.clone());
+        mem.ppu.timing.pal = hdr.pal();
+        mem.apu.pal = hdr.pal();
+
+        // The GSU-2 is needed past 1MB of ROM, or asked for by $1A
+        if hdr.superfx() {
+            let gsu2 = hdr.rom_size() > 0x100000 || hdr.rom_type() == 0x1A;
+            let chip = SuperFx::new(rom, gsu2);
+            println!("Coprocessor: {}", chip.name());
+            *mem.coprocessor.borrow_mut() = Some(Box::new(chip));
+        }
 
         SNES {
             cart: cart,
             cpu: cpu,
             mem: mem,
             step: 0u64,
+            clock: 0u64,
+            frames: 0u64,
+            script: None,
+            audio: audio::shared_sink(),
         }
     }
 
     pub fn reset(&mut self) {
         println!("SNES Reset");
         self.cpu.reset(&self.cart);
+        self.mem.ppu.reset();
+        self.mem.apu.reset();
+        if let Some(ref mut chip) = *self.mem.coprocessor.borrow_mut() {
+            chip.reset();
+        }
     }
 
+    // Runs one instruction, then everything else up to where it
+    // Left the CPU, including any time DMA held the CPU for
     pub fn step(&mut self) -> Result<u8, String> {
         self.step += 1;
-        self.cpu.step(&mut self.mem)
+        let cycles = self.cpu.step(&mut self.mem)?;
+        self.cpu.alu_step(cycles);
+
+        // DMA started by the instruction and HDMA started on the
+        // Way both halt the CPU, keep going until they're done.
+        // DMA moves a byte at a time so the beam, the IRQs and
+        // The other chips keep up with it
+        let mut clocks = self.cpu.master_clocks(cycles);
+        while clocks > 0 {
+            self.advance(clocks);
+            clocks = self.cpu.take_stall() + self.cpu.dma_step(&mut self.mem);
+        }
+
+        Ok(cycles)
+    }
+
+    // Runs whole instructions until at least `clocks` master
+    // Clocks have gone by, returns how many did
+    pub fn run_cycles(&mut self, clocks: u64) -> Result<u64, String> {
+        let start = self.clock;
+        while self.clock - start < clocks {
+            self.step()?;
+        }
+        Ok(self.clock - start)
+    }
+
+    // Runs up to the start of the next frame
+    pub fn run_frame(&mut self) -> Result<(), String> {
+        let frame = self.frames;
+        while self.frames == frame {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // Moves the beam along, stopping at every event on the way,
+    // Then catches up the chips running on their own clocks
+    fn advance(&mut self, clocks: u32) {
+        let mut left = clocks;
+        while left > 0 {
+            let line = self.mem.ppu.timing.vcounter;
+            let start = self.mem.ppu.timing.hclock;
+            let (used, event) = self.mem.ppu.timing.advance(left);
+            left -= used;
+
+            self.cpu.irq_poll(line, start, start + used);
+            self.light_poll(line, start, start + used);
+
+            if let Some(event) = event {
+                self.event(event);
+            }
+        }
+        self.clock += clocks as u64;
+
+        self.mem.apu.run(clocks);
+        if let Some(ref mut chip) = *self.mem.coprocessor.borrow_mut() {
+            chip.run(clocks);
+        }
+    }
+
+    // A light gun on port 2 pulls the IO line low as the beam
+    // Passes its sensor, which latches the counters if WRIO allows
+    fn light_poll(&mut self, line: u16, start: u32, end: u32) {
+        let sensor = self.mem.port2.borrow().sensor();
+        if let Some((dot, sensor_line)) = sensor {
+            let clock = dot as u32 * 4;
+            if sensor_line == line && clock > start && clock <= end && self.mem.ppu.extlatch {
+                self.mem.ppu.latch_position(dot, line);
+            }
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::NewFrame => {
+                self.frames += 1;
+
+                if let Some(ref mut script) = self.script {
+                    script.apply(unsafe { Scrn::FRAME_COUNT });
+                }
+
+                self.cpu.new_frame();
+                self.mem.ppu.new_frame();
+
+                // Mutes come from the window and the debugger, the
+                // Voices go back for them to show
+                self.mem.apu.dsp.mute = Scrn::VOICE_MUTE.load(Ordering::SeqCst);
+                self.mem.apu.dsp.echo_mute = Scrn::ECHO_MUTE.load(Ordering::SeqCst);
+
+                unsafe {
+                    for v in 0..8 {
+                        Scrn::VOICES[v] = self.mem.apu.dsp.voice_state(v);
+                    }
+                }
+
+                if let Some(ref mut sink) = *self.audio.lock().unwrap() {
+                    sink.write(&self.mem.apu.samples);
+                }
+                self.mem.apu.samples.clear();
+            }
+            Event::HdmaInit => self.cpu.hdma_init(&mut self.mem),
+            Event::Render(line) => {
+                self.mem.ppu.mosaic_scanline(line);
+                self.mem.ppu.render_scanline(line);
+            }
+            Event::HBlank => self.cpu.hdma_run(&mut self.mem),
+            Event::VBlank => {
+                self.mem.ppu.end_frame();
+                self.cpu.vblank();
+            }
+            Event::AutoJoypad => self.cpu.auto_joypad(&self.mem),
+        }
     }
 }
 
@@ -0,0 +1,530 @@
+use apu::{Apu, Psw, IPL_ROM};
+use audio::{AudioSink, DSP_RATE};
+use cart::SnesHeader;
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+pub const SPC_SIGNATURE: &'static [u8] = b"SNES-SPC700 Sound File Data v0.30";
+
+const SPC_SIZE: usize = 0x10200;
+const RAM_OFFSET: usize = 0x100;
+const DSP_OFFSET: usize = 0x10100;
+const EXTRA_OFFSET: usize = 0x101C0;
+const XID6_OFFSET: usize = 0x10200;
+
+// Lengths in xid6 are counted in 1/64000ths of a second
+const XID6_TICKS: u32 = 64000;
+
+// What the tags say made a dump, 0 is unknown
+const EMULATOR_OTHER: u8 = 0;
+
+// Used when the tags don't say how long to play
+pub const DEFAULT_SECONDS: u32 = 180;
+pub const DEFAULT_FADE: u32 = 10000;
+
+// ID666 tags, and the xid6 ones that override or add to them
+#[derive(Debug, Clone, Default)]
+pub struct SpcTags {
+    pub song: String,
+    pub game: String,
+    pub dumper: String,
+    pub comments: String,
+    pub date: String,
+    pub artist: String,
+    // Seconds played before fading out, and the fade in ms
+    pub seconds: u32,
+    pub fade: u32,
+    pub muted: u8,
+    pub emulator: u8,
+    pub ost_title: String,
+    pub ost_disc: u8,
+    pub ost_track: u16,
+    pub publisher: String,
+    pub copyright: u16,
+    pub loops: u8,
+    pub amplification: u32,
+}
+
+#[derive(Clone)]
+pub struct Spc {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub psw: u8,
+    pub sp: u8,
+    pub tags: SpcTags,
+    pub aram: Vec<u8>,
+    pub dsp: Vec<u8>,
+    // The RAM under the IPL ROM, the copy in `aram` may be the ROM
+    pub extra: Vec<u8>,
+}
+
+impl Spc {
+    pub fn load(path: &str) -> Result<Spc, String> {
+        let mut file = File::open(path).map_err(|err| format!("Could not open SPC: {}", err))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|err| format!("Could not read SPC: {}", err))?;
+
+        Spc::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Spc, String> {
+        if data.len() < SPC_SIZE {
+            return Err(format!("SPC is too short: {} bytes", data.len()));
+        }
+        if &data[..SPC_SIGNATURE.len()] != SPC_SIGNATURE {
+            return Err(String::from("Not an SPC file"));
+        }
+
+        let mut spc = Spc {
+            pc: (data[0x25] as u16) | ((data[0x26] as u16) << 8),
+            a: data[0x27],
+            x: data[0x28],
+            y: data[0x29],
+            psw: data[0x2A],
+            sp: data[0x2B],
+            tags: SpcTags::default(),
+            aram: data[RAM_OFFSET..RAM_OFFSET + 0x10000].to_vec(),
+            dsp: data[DSP_OFFSET..DSP_OFFSET + 0x80].to_vec(),
+            extra: data[EXTRA_OFFSET..EXTRA_OFFSET + 0x40].to_vec(),
+        };
+
+        // $1A at $23 says the ID666 tags are there, $1B that they aren't
+        if data[0x23] == 0x1A {
+            spc.tags = Spc::id666(data);
+        }
+
+        // The header says how long the chunk is, anything after
+        // It isn't ours to read
+        if data.len() >= XID6_OFFSET + 8 && &data[XID6_OFFSET..XID6_OFFSET + 4] == b"xid6" {
+            let size = read_u32(&data[XID6_OFFSET + 4..]) as usize;
+            let end = ::std::cmp::min(data.len() - (XID6_OFFSET + 8), size);
+            spc.xid6(&data[XID6_OFFSET + 8..XID6_OFFSET + 8 + end]);
+        }
+
+        Ok(spc)
+    }
+
+    // The tags come in a text and a binary layout which only
+    // Differ after the date, guess from what the lengths hold
+    fn id666(data: &[u8]) -> SpcTags {
+        let text = (data[0xA9..0xB1].iter().all(|&c| c == 0 || (c >= b'0' && c <= b'9'))
+            && data[0xA9..0xB1].iter().any(|&c| c != 0))
+            || data[0x9E..0xA9].contains(&b'/');
+
+        let mut tags = SpcTags {
+            song: string(&data[0x2E..0x4E]),
+            game: string(&data[0x4E..0x6E]),
+            dumper: string(&data[0x6E..0x7E]),
+            comments: string(&data[0x7E..0x9E]),
+            ..SpcTags::default()
+        };
+
+        if text {
+            tags.date = string(&data[0x9E..0xA9]);
+            tags.seconds = string(&data[0xA9..0xAC]).parse().unwrap_or(0);
+            tags.fade = string(&data[0xAC..0xB1]).parse().unwrap_or(0);
+            tags.artist = string(&data[0xB1..0xD1]);
+            tags.muted = data[0xD1];
+            tags.emulator = data[0xD2];
+        } else {
+            let date = read_u32(&data[0x9E..]);
+            if date != 0 {
+                tags.date = format!("{:02}/{:02}/{:04}", (date >> 8) & 0xFF, date & 0xFF, date >> 16);
+            }
+            tags.seconds = read_u32(&data[0xA9..]) & 0xFFFFFF;
+            tags.fade = read_u32(&data[0xAC..]);
+            tags.artist = string(&data[0xB0..0xD0]);
+            tags.muted = data[0xD0];
+            tags.emulator = data[0xD1];
+        }
+
+        tags
+    }
+
+    // Sub-chunks of an ID byte, a type byte and either the data
+    // Itself or its length followed by it, padded to four bytes
+    fn xid6(&mut self, chunk: &[u8]) {
+        let (mut intro, mut looped, mut end, mut fade) = (None, 0u32, 0u32, None);
+
+        let mut i = 0;
+        while i + 4 <= chunk.len() {
+            let id = chunk[i];
+            let kind = chunk[i + 1];
+            let value = (chunk[i + 2] as u16) | ((chunk[i + 3] as u16) << 8);
+            i += 4;
+
+            // Type 0 keeps the data in the header
+            let data = match kind {
+                0 => &chunk[i..i],
+                _ => {
+                    let len = value as usize;
+                    if i + len > chunk.len() {
+                        break;
+                    }
+                    let data = &chunk[i..i + len];
+                    i += (len + 3) & !3;
+                    data
+                }
+            };
+            let number = match kind {
+                0 => value as u32,
+                _ if data.len() >= 4 => read_u32(data),
+                _ => 0,
+            };
+
+            match id {
+                0x01 => self.tags.song = string(data),
+                0x02 => self.tags.game = string(data),
+                0x03 => self.tags.artist = string(data),
+                0x04 => self.tags.dumper = string(data),
+                0x05 => self.tags.date = format!("{:02}/{:02}/{:04}", (number >> 8) & 0xFF, number & 0xFF, number >> 16),
+                0x06 => self.tags.emulator = number as u8,
+                0x07 => self.tags.comments = string(data),
+                0x10 => self.tags.ost_title = string(data),
+                0x11 => self.tags.ost_disc = number as u8,
+                0x12 => self.tags.ost_track = number as u16,
+                0x13 => self.tags.publisher = string(data),
+                0x14 => self.tags.copyright = number as u16,
+                0x30 => intro = Some(number),
+                0x31 => looped = number,
+                0x32 => end = number,
+                0x33 => fade = Some(number),
+                0x34 => self.tags.muted = number as u8,
+                0x35 => self.tags.loops = number as u8,
+                0x36 => self.tags.amplification = number,
+                _ => { }
+            }
+        }
+
+        if let Some(intro) = intro {
+            // Up to 255 loops of a 32 bit length overflow 32 bits
+            let loops = if self.tags.loops == 0 { 1 } else { self.tags.loops as u64 };
+            let ticks = intro as u64 + looped as u64 * loops + end as u64;
+            let ticks = ::std::cmp::min(ticks, ::std::u32::MAX as u64) as u32;
+            self.tags.seconds = ticks / XID6_TICKS;
+        }
+        if let Some(fade) = fade {
+            self.tags.fade = fade / (XID6_TICKS / 1000);
+        }
+    }
+
+    // Snapshot of the sound side of a running SNES
+    pub fn from_apu(apu: &Apu, tags: SpcTags) -> Spc {
+        let mut aram = apu.aram.clone();
+        let extra = aram[0xFFC0..].to_vec();
+
+        aram[0xF0..0x100].copy_from_slice(&apu.save_io());
+        // Other players expect the ROM here while it is mapped
+        if aram[0xF1] & 0x80 == 0x80 {
+            aram[0xFFC0..].copy_from_slice(&IPL_ROM);
+        }
+
+        Spc {
+            pc: apu.pc,
+            a: apu.a,
+            x: apu.x,
+            y: apu.y,
+            psw: apu.psw.bits(),
+            sp: apu.sp,
+            tags: tags,
+            aram: aram,
+            dsp: apu.dsp.regs[..0x80].to_vec(),
+            extra: extra,
+        }
+    }
+
+    // Text ID666 tags, which every player understands
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; SPC_SIZE];
+
+        data[..SPC_SIGNATURE.len()].copy_from_slice(SPC_SIGNATURE);
+        data[0x21] = 0x1A;
+        data[0x22] = 0x1A;
+        data[0x23] = 0x1A;
+        data[0x24] = 30;
+
+        data[0x25] = self.pc as u8;
+        data[0x26] = (self.pc >> 8) as u8;
+        data[0x27] = self.a;
+        data[0x28] = self.x;
+        data[0x29] = self.y;
+        data[0x2A] = self.psw;
+        data[0x2B] = self.sp;
+
+        let tags = &self.tags;
+        put_string(&mut data[0x2E..0x4E], &tags.song);
+        put_string(&mut data[0x4E..0x6E], &tags.game);
+        put_string(&mut data[0x6E..0x7E], &tags.dumper);
+        put_string(&mut data[0x7E..0x9E], &tags.comments);
+        put_string(&mut data[0x9E..0xA9], &tags.date);
+        put_string(&mut data[0xA9..0xAC], &format!("{}", tags.seconds));
+        put_string(&mut data[0xAC..0xB1], &format!("{}", tags.fade));
+        put_string(&mut data[0xB1..0xD1], &tags.artist);
+        data[0xD1] = tags.muted;
+        data[0xD2] = tags.emulator;
+
+        data[RAM_OFFSET..RAM_OFFSET + 0x10000].copy_from_slice(&self.aram);
+        data[DSP_OFFSET..DSP_OFFSET + 0x80].copy_from_slice(&self.dsp);
+        data[EXTRA_OFFSET..EXTRA_OFFSET + 0x40].copy_from_slice(&self.extra);
+
+        data
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut file = File::create(path).map_err(|err| format!("Could not create SPC: {}", err))?;
+        file.write_all(&self.to_bytes()).map_err(|err| format!("Could not write SPC: {}", err))
+    }
+
+    // Puts the sound side of the SNES into the state of the snapshot
+    pub fn apply(&self, apu: &mut Apu) {
+        apu.reset();
+
+        apu.aram.copy_from_slice(&self.aram);
+        apu.aram[0xFFC0..].copy_from_slice(&self.extra);
+        apu.load_io(&self.aram[0xF0..0x100]);
+        apu.dsp.load(&self.dsp);
+
+        apu.pc = self.pc;
+        apu.a = self.a;
+        apu.x = self.x;
+        apu.y = self.y;
+        apu.psw = Psw::from_bits_truncate(self.psw);
+        apu.sp = self.sp;
+    }
+}
+
+fn string(data: &[u8]) -> String {
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+// Cut to fit, the rest of the field stays zeroed
+fn put_string(field: &mut [u8], value: &str) {
+    let bytes = value.as_bytes();
+    let len = ::std::cmp::min(bytes.len(), field.len());
+    field[..len].copy_from_slice(&bytes[..len]);
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) | ((data[1] as u32) << 8) | ((data[2] as u32) << 16) | ((data[3] as u32) << 24)
+}
+
+// Tags for a dump of a running game
+pub fn tags(header: &SnesHeader, frame: u64) -> SpcTags {
+    SpcTags {
+        game: String::from(header.game_title.trim()),
+        dumper: String::from("snes-emu"),
+        comments: format!("Dumped at frame {}", frame),
+        seconds: DEFAULT_SECONDS,
+        fade: DEFAULT_FADE,
+        emulator: EMULATOR_OTHER,
+        ..SpcTags::default()
+    }
+}
+
+// Plays `seconds` of the snapshot and then fades out over `fade` ms,
+// Returns a checksum of the samples to spot changes in the sound core
+pub fn render(spc: &Spc, sink: &mut AudioSink, seconds: u32, fade: u32) -> u32 {
+    let mut apu = Apu::new();
+    spc.apply(&mut apu);
+
+    let play = seconds as u64 * DSP_RATE as u64;
+    let fade = fade as u64 * DSP_RATE as u64 / 1000;
+    let total = play + fade;
+
+    // A tenth of a second at a time
+    let chunk = (DSP_RATE / 10) as u64;
+
+    let mut checksum = 0x811C9DC5u32;
+    let mut done = 0u64;
+    let mut out = Vec::new();
+    while done < total {
+        let frames = ::std::cmp::min(chunk, total - done) as usize;
+        apu.run_samples(frames * 2);
+
+        out.clear();
+        for (i, frame) in apu.samples[..frames * 2].chunks(2).enumerate() {
+            let n = done + i as u64;
+            for &sample in frame {
+                checksum = (checksum ^ (sample as u16 as u32)).wrapping_mul(0x01000193);
+
+                let sample = if n < play {
+                    sample
+                } else {
+                    (sample as i64 * (total - n) as i64 / fade as i64) as i16
+                };
+                out.push(sample);
+            }
+        }
+        sink.write(&out);
+
+        apu.samples.drain(..frames * 2);
+        done += frames as u64;
+    }
+
+    sink.finish();
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audio::NullSink;
+
+    // A square wave looping on voice 0, the SPC700 spins in place
+    fn synthetic() -> Spc {
+        let mut aram = vec![0u8; 0x10000];
+        aram[0x0400] = 0x2F;
+        aram[0x0401] = 0xFE;
+
+        // Directory at $0200, one sample at $0300 looping on itself
+        aram[0x0200] = 0x00;
+        aram[0x0201] = 0x03;
+        aram[0x0202] = 0x00;
+        aram[0x0203] = 0x03;
+        aram[0x0300] = 0xB3;
+        for i in 0..8 {
+            aram[0x0301 + i] = if i < 4 { 0x77 } else { 0x99 };
+        }
+
+        let mut dsp = vec![0u8; 0x80];
+        dsp[0x00] = 0x7F;
+        dsp[0x01] = 0x7F;
+        dsp[0x03] = 0x10;
+        dsp[0x05] = 0x8F;
+        dsp[0x06] = 0xE0;
+        dsp[0x0C] = 0x7F;
+        dsp[0x1C] = 0x7F;
+        dsp[0x5D] = 0x02;
+        dsp[0x4C] = 0x01;
+        dsp[0x6C] = 0x20;
+
+        Spc {
+            pc: 0x0400,
+            a: 0,
+            x: 0,
+            y: 0,
+            psw: 0,
+            sp: 0xEF,
+            tags: SpcTags {
+                song: String::from("Square"),
+                game: String::from("Test"),
+                artist: String::from("snes-emu"),
+                date: String::from("10/18/2026"),
+                seconds: 2,
+                fade: 500,
+                ..SpcTags::default()
+            },
+            aram: aram,
+            dsp: dsp,
+            extra: vec![0u8; 0x40],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let spc = synthetic();
+        let parsed = Spc::parse(&spc.to_bytes()).unwrap();
+
+        assert_eq!(parsed.pc, spc.pc);
+        assert_eq!(parsed.sp, spc.sp);
+        assert_eq!(parsed.tags.song, "Square");
+        assert_eq!(parsed.tags.game, "Test");
+        assert_eq!(parsed.tags.artist, "snes-emu");
+        assert_eq!(parsed.tags.date, "10/18/2026");
+        assert_eq!(parsed.tags.seconds, 2);
+        assert_eq!(parsed.tags.fade, 500);
+        assert!(parsed.aram == spc.aram);
+        assert!(parsed.dsp == spc.dsp);
+        assert!(parsed.extra == spc.extra);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(Spc::parse(&[0u8; 16]).is_err());
+        assert!(Spc::parse(&vec![0u8; SPC_SIZE]).is_err());
+    }
+
+    #[test]
+    fn xid6_lengths() {
+        let mut data = synthetic().to_bytes();
+        let mut chunk = Vec::new();
+        // Intro of 10s, a 5s loop played twice and a 2s fade
+        for &(id, value) in &[(0x30u8, 10 * XID6_TICKS), (0x31, 5 * XID6_TICKS), (0x33, 2 * XID6_TICKS)] {
+            chunk.extend_from_slice(&[id, 4, 4, 0]);
+            chunk.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+        }
+        chunk.extend_from_slice(&[0x35, 0, 2, 0]);
+        chunk.extend_from_slice(&[0x01, 1, 5, 0]);
+        chunk.extend_from_slice(b"Loop\0\0\0\0");
+
+        data.extend_from_slice(b"xid6");
+        let len = chunk.len() as u32;
+        data.extend_from_slice(&[len as u8, (len >> 8) as u8, 0, 0]);
+        data.extend_from_slice(&chunk);
+
+        let spc = Spc::parse(&data).unwrap();
+        assert_eq!(spc.tags.loops, 2);
+        assert_eq!(spc.tags.seconds, 20);
+        assert_eq!(spc.tags.fade, 2000);
+        assert_eq!(spc.tags.song, "Loop");
+    }
+
+    #[test]
+    fn xid6_overflow() {
+        let mut data = synthetic().to_bytes();
+        let mut chunk = Vec::new();
+        for &id in &[0x30u8, 0x31] {
+            chunk.extend_from_slice(&[id, 4, 4, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        }
+        chunk.extend_from_slice(&[0x35, 0, 0xFF, 0]);
+
+        // Trailing bytes past the declared size are not tags
+        data.extend_from_slice(b"xid6");
+        let len = chunk.len() as u32;
+        data.extend_from_slice(&[len as u8, (len >> 8) as u8, 0, 0]);
+        data.extend_from_slice(&chunk);
+        data.extend_from_slice(&[0x01, 1, 5, 0]);
+        data.extend_from_slice(b"Junk\0\0\0\0");
+
+        let spc = Spc::parse(&data).unwrap();
+        assert_eq!(spc.tags.seconds, ::std::u32::MAX / XID6_TICKS);
+        assert_eq!(spc.tags.song, "Square");
+    }
+
+    struct Peak {
+        samples: usize,
+        peak: i32,
+    }
+
+    impl AudioSink for Peak {
+        fn write(&mut self, samples: &[i16]) {
+            self.samples += samples.len();
+            for &s in samples {
+                self.peak = ::std::cmp::max(self.peak, (s as i32).abs());
+            }
+        }
+    }
+
+    #[test]
+    fn render_length() {
+        let mut sink = Peak { samples: 0, peak: 0 };
+        render(&synthetic(), &mut sink, 1, 500);
+        assert_eq!(sink.samples, (DSP_RATE as usize + DSP_RATE as usize / 2) * 2);
+        assert!(sink.peak > 1000);
+    }
+
+    // The same file renders the same sound every time
+    #[test]
+    fn render_deterministic() {
+        let mut sink = Peak { samples: 0, peak: 0 };
+        let first = render(&synthetic(), &mut sink, 1, 100);
+        let second = render(&synthetic(), &mut NullSink, 1, 100);
+        assert!(sink.peak > 0);
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,1256 @@
+use coprocessor::Coprocessor;
+
+use std::sync::Arc;
+
+bitflags! {
+    #[derive(Default)]
+    pub flags Sfr: u16 {
+        const SFR_Z    = 0b0000000000000010,
+        const SFR_CY   = 0b0000000000000100,
+        const SFR_S    = 0b0000000000001000,
+        const SFR_OV   = 0b0000000000010000,
+        // Go, set while the GSU is running
+        const SFR_G    = 0b0000000000100000,
+        // Set while reading ROM through R14
+        const SFR_R    = 0b0000000001000000,
+        const SFR_ALT1 = 0b0000000100000000,
+        const SFR_ALT2 = 0b0000001000000000,
+        const SFR_IL   = 0b0000010000000000,
+        const SFR_IH   = 0b0000100000000000,
+        // Set by WITH, turns TO and FROM into MOVE and MOVES
+        const SFR_B    = 0b0001000000000000,
+        const SFR_IRQ  = 0b1000000000000000,
+    }
+}
+
+// Plot options, set by CMODE
+const POR_TRANSPARENT: u8 = 0x01;
+const POR_DITHER: u8 = 0x02;
+const POR_HIGH_NIBBLE: u8 = 0x04;
+const POR_FREEZE_HIGH: u8 = 0x08;
+const POR_OBJ: u8 = 0x10;
+
+// Screen mode, bits 0-1 are the color depth, 2 and 5 the height
+const SCMR_RAN: u8 = 0x08;
+const SCMR_RON: u8 = 0x10;
+
+// CFGR bit 7 masks the IRQ on STOP, bit 5 speeds up the multiplier
+const CFGR_IRQ: u8 = 0x80;
+const CFGR_MS0: u8 = 0x20;
+
+// While the GSU owns the ROM the CPU reads these instead, which
+// Points every interrupt vector into WRAM at $0100-$010C
+static ROM_VECTORS: [u8; 16] = [
+    0x00, 0x01, 0x00, 0x01, 0x04, 0x01, 0x00, 0x01,
+    0x00, 0x01, 0x08, 0x01, 0x00, 0x01, 0x0C, 0x01,
+];
+
+const CACHE_SIZE: usize = 512;
+const CACHE_LINES: usize = 32;
+
+// Plotted pixels are gathered 8 at a time, one row of a
+// Character, before going out to RAM
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelCache {
+    offset: u16,
+    bitpend: u8,
+    data: [u8; 8],
+}
+
+// The Super FX, the MARIO chip of Star Fox (GSU-1) and the
+// GSU-2 of Yoshi's Island and Doom which can run at 21 MHz
+#[derive(Clone)]
+pub struct SuperFx {
+    gsu2: bool,
+    rom: Arc<Vec<u8>>,
+    ram: Vec<u8>,
+    r: [u16; 16],
+    sfr: Sfr,
+    sreg: usize,
+    dreg: usize,
+    pbr: u8,
+    rombr: u8,
+    rambr: u8,
+    cbr: u16,
+    scbr: u8,
+    scmr: u8,
+    colr: u8,
+    por: u8,
+    bramr: u8,
+    cfgr: u8,
+    clsr: u8,
+    // The byte after the instruction running, already fetched
+    pipeline: u8,
+    r14_modified: bool,
+    r15_modified: bool,
+    ramaddr: u16,
+    rombuffer: u8,
+    cache: Vec<u8>,
+    cache_valid: [bool; CACHE_LINES],
+    pixelcache: [PixelCache; 2],
+    // Master clocks taken by the instruction running
+    cycles: u32,
+    clock_debt: i64,
+}
+
+impl SuperFx {
+    pub fn new(rom: Vec<u8>, gsu2: bool) -> SuperFx {
+        // The extended header has the RAM size when $FFDA is $33
+        let ram_size = if rom.len() > 0x7FDA && rom[0x7FDA] == 0x33 && rom[0x7FBD] != 0 {
+            0x400 << (rom[0x7FBD] & 0x07)
+        } else {
+            0x10000
+        };
+
+        let mut gsu = SuperFx {
+            gsu2: gsu2,
+            rom: Arc::new(rom),
+            ram: vec![0u8; ram_size],
+            r: [0u16; 16],
+            sfr: Sfr::empty(),
+            sreg: 0,
+            dreg: 0,
+            pbr: 0u8,
+            rombr: 0u8,
+            rambr: 0u8,
+            cbr: 0u16,
+            scbr: 0u8,
+            scmr: 0u8,
+            colr: 0u8,
+            por: 0u8,
+            bramr: 0u8,
+            cfgr: 0u8,
+            clsr: 0u8,
+            pipeline: 0x01,
+            r14_modified: false,
+            r15_modified: false,
+            ramaddr: 0u16,
+            rombuffer: 0u8,
+            cache: vec![0u8; CACHE_SIZE],
+            cache_valid: [false; CACHE_LINES],
+            pixelcache: [PixelCache::default(); 2],
+            cycles: 0u32,
+            clock_debt: 0i64,
+        };
+        gsu.power();
+        gsu
+    }
+
+    fn power(&mut self) {
+        self.r = [0u16; 16];
+        self.sfr = Sfr::empty();
+        self.sreg = 0;
+        self.dreg = 0;
+        self.pbr = 0u8;
+        self.rombr = 0u8;
+        self.rambr = 0u8;
+        self.cbr = 0u16;
+        self.scbr = 0u8;
+        self.scmr = 0u8;
+        self.colr = 0u8;
+        self.por = 0u8;
+        self.bramr = 0u8;
+        self.cfgr = 0u8;
+        self.clsr = 0u8;
+        self.pipeline = 0x01;
+        self.r14_modified = false;
+        self.r15_modified = false;
+        self.ramaddr = 0u16;
+        self.rombuffer = 0u8;
+        self.pixelcache = [PixelCache::default(); 2];
+        self.clock_debt = 0i64;
+        self.cache_flush();
+    }
+
+    // Memory takes 5 clocks at 21 MHz and 6 at 10.7 MHz,
+    // Cache and internal cycles 1 and 2
+    fn mem_clocks(&self) -> u32 {
+        if self.clsr & 1 == 1 { 5 } else { 6 }
+    }
+
+    fn fast_clocks(&self) -> u32 {
+        if self.clsr & 1 == 1 { 1 } else { 2 }
+    }
+
+    fn rom_offset(&self, bank: u8, addr: u16) -> usize {
+        // Banks $00-$3F are mapped like LoROM, $40-$5F linearly
+        let offset = match bank & 0x40 {
+            0 => (((bank & 0x3F) as usize) << 15) | (addr & 0x7FFF) as usize,
+            _ => (((bank & 0x1F) as usize) << 16) | addr as usize,
+        };
+        offset % self.rom.len()
+    }
+
+    fn rom_read(&self, bank: u8, addr: u16) -> u8 {
+        self.rom[self.rom_offset(bank, addr)]
+    }
+
+    fn ram_offset(&self, bank: u8, addr: u16) -> usize {
+        ((((bank & 0x01) as usize) << 16) | addr as usize) & (self.ram.len() - 1)
+    }
+
+    // Anything the GSU can run code from or read through R14
+    fn bus_read(&self, bank: u8, addr: u16) -> u8 {
+        match bank {
+            0x00...0x5F => self.rom_read(bank, addr),
+            _ => self.ram[self.ram_offset(bank, addr)],
+        }
+    }
+
+    fn ram_read(&mut self, addr: u16) -> u8 {
+        self.cycles += self.mem_clocks();
+        let offset = self.ram_offset(self.rambr, addr);
+        self.ram[offset]
+    }
+
+    fn ram_write(&mut self, addr: u16, val: u8) {
+        self.cycles += self.mem_clocks();
+        let offset = self.ram_offset(self.rambr, addr);
+        self.ram[offset] = val;
+    }
+
+    // Words in RAM swap bytes on odd addresses
+    fn ram_read_u16(&mut self, addr: u16) -> u16 {
+        (self.ram_read(addr) as u16) | ((self.ram_read(addr ^ 1) as u16) << 8)
+    }
+
+    fn ram_write_u16(&mut self, addr: u16, val: u16) {
+        self.ram_write(addr, val as u8);
+        self.ram_write(addr ^ 1, (val >> 8) as u8);
+    }
+
+    fn cache_flush(&mut self) {
+        for valid in self.cache_valid.iter_mut() {
+            *valid = false;
+        }
+    }
+
+    // The 512 bytes from CBR on come from the cache, a line of
+    // 16 bytes is filled the first time any of it is run
+    fn read_opcode(&mut self, addr: u16) -> u8 {
+        let offset = addr.wrapping_sub(self.cbr) as usize;
+        if offset < CACHE_SIZE {
+            let line = offset >> 4;
+            if !self.cache_valid[line] {
+                let start = offset & 0x1F0;
+                let pbr = self.pbr;
+                for n in 0..16 {
+                    let src = self.cbr.wrapping_add((start + n) as u16);
+                    self.cycles += self.mem_clocks();
+                    self.cache[start + n] = self.bus_read(pbr, src);
+                }
+                self.cache_valid[line] = true;
+            } else {
+                self.cycles += self.fast_clocks();
+            }
+            return self.cache[offset];
+        }
+
+        self.cycles += self.mem_clocks();
+        let pbr = self.pbr;
+        self.bus_read(pbr, addr)
+    }
+
+    // The next opcode, fetching the one after it
+    fn peekpipe(&mut self) -> u8 {
+        let result = self.pipeline;
+        let pc = self.r[15];
+        self.pipeline = self.read_opcode(pc);
+        self.r15_modified = false;
+        result
+    }
+
+    // An operand byte of the instruction running
+    fn pipe(&mut self) -> u8 {
+        let result = self.pipeline;
+        self.r[15] = self.r[15].wrapping_add(1);
+        let pc = self.r[15];
+        self.pipeline = self.read_opcode(pc);
+        self.r15_modified = false;
+        result
+    }
+
+    fn set_reg(&mut self, n: usize, val: u16) {
+        self.r[n] = val;
+        match n {
+            14 => self.r14_modified = true,
+            15 => self.r15_modified = true,
+            _ => { }
+        }
+    }
+
+    fn sr(&self) -> u16 {
+        self.r[self.sreg]
+    }
+
+    fn set_dr(&mut self, val: u16) {
+        let dreg = self.dreg;
+        self.set_reg(dreg, val);
+    }
+
+    fn set_sz(&mut self, val: u16) {
+        self.sfr.set(SFR_S, val & 0x8000 != 0);
+        self.sfr.set(SFR_Z, val == 0);
+    }
+
+    // Every instruction but the prefixes ends with this
+    fn reset_prefix(&mut self) {
+        self.sfr.remove(SFR_B | SFR_ALT1 | SFR_ALT2);
+        self.sreg = 0;
+        self.dreg = 0;
+    }
+
+    fn color(&self, source: u8) -> u8 {
+        if self.por & POR_HIGH_NIBBLE != 0 {
+            return (self.colr & 0xF0) | (source >> 4);
+        }
+        if self.por & POR_FREEZE_HIGH != 0 {
+            return (self.colr & 0xF0) | (source & 0x0F);
+        }
+        source
+    }
+
+    // 2, 4 or 8 bits per pixel, modes 1 and 2 are both 4
+    fn bpp(&self) -> usize {
+        match self.scmr & 3 {
+            0 => 2,
+            3 => 8,
+            _ => 4,
+        }
+    }
+
+    // The height bits pick how characters are laid out, 128,
+    // 160 or 192 lines in columns or the 16x16 grid of OBJ mode
+    fn char_addr(&self, x: u8, y: u8) -> usize {
+        let ht = ((self.scmr >> 2) & 1) | ((self.scmr >> 4) & 2);
+        let (x, y) = (x as usize, y as usize);
+        let cn = match if self.por & POR_OBJ != 0 { 3 } else { ht } {
+            0 => ((x & 0xF8) << 1) + ((y & 0xF8) >> 3),
+            1 => ((x & 0xF8) << 1) + ((x & 0xF8) >> 1) + ((y & 0xF8) >> 3),
+            2 => ((x & 0xF8) << 1) + (x & 0xF8) + ((y & 0xF8) >> 3),
+            _ => ((y & 0x80) << 2) + ((x & 0x80) << 1) + ((y & 0x78) << 1) + ((x & 0x78) >> 3),
+        };
+
+        cn * (self.bpp() << 3) + ((self.scbr as usize) << 10) + (y & 7) * 2
+    }
+
+    fn plot(&mut self, x: u8, y: u8) {
+        // Dithering picks a nibble on alternate pixels, and it's
+        // That nibble which is tested for transparency
+        let mut color = self.colr;
+        if self.por & POR_DITHER != 0 && self.scmr & 3 != 3 {
+            if (x ^ y) & 1 == 1 {
+                color >>= 4;
+            }
+            color &= 0x0F;
+        }
+
+        if self.por & POR_TRANSPARENT == 0 {
+            let opaque = match (self.scmr & 3 == 3, self.por & POR_FREEZE_HIGH != 0) {
+                (true, false) => color != 0,
+                _ => color & 0x0F != 0,
+            };
+            if !opaque {
+                return;
+            }
+        }
+
+        let offset = ((y as u16) << 5) + (x as u16 >> 3);
+        if offset != self.pixelcache[0].offset {
+            self.flush_pixel_cache(1);
+            self.pixelcache[1] = self.pixelcache[0];
+            self.pixelcache[0].bitpend = 0;
+            self.pixelcache[0].offset = offset;
+        }
+
+        let bit = ((x & 7) ^ 7) as usize;
+        self.pixelcache[0].data[bit] = color;
+        self.pixelcache[0].bitpend |= 1 << bit;
+        if self.pixelcache[0].bitpend == 0xFF {
+            self.flush_pixel_cache(1);
+            self.pixelcache[1] = self.pixelcache[0];
+            self.pixelcache[0].bitpend = 0;
+        }
+    }
+
+    // Writes a row of plotted pixels out as bitplanes, reading
+    // The row back first unless all 8 pixels were plotted
+    fn flush_pixel_cache(&mut self, index: usize) {
+        let cache = self.pixelcache[index];
+        if cache.bitpend == 0 {
+            return;
+        }
+
+        let x = (cache.offset << 3) as u8;
+        let y = (cache.offset >> 5) as u8;
+        let addr = self.char_addr(x, y);
+
+        for n in 0..self.bpp() {
+            let byte = ((n >> 1) << 4) + (n & 1);
+            let mut data = 0u8;
+            for bit in 0..8 {
+                data |= ((cache.data[bit] >> n) & 1) << bit;
+            }
+
+            let offset = (addr + byte) & (self.ram.len() - 1);
+            if cache.bitpend != 0xFF {
+                self.cycles += self.mem_clocks();
+                data &= cache.bitpend;
+                data |= self.ram[offset] & !cache.bitpend;
+            }
+            self.cycles += self.mem_clocks();
+            self.ram[offset] = data;
+        }
+
+        self.pixelcache[index].bitpend = 0;
+    }
+
+    fn rpix(&mut self, x: u8, y: u8) -> u8 {
+        self.flush_pixel_cache(1);
+        self.flush_pixel_cache(0);
+
+        let addr = self.char_addr(x, y);
+        let bit = (x & 7) ^ 7;
+
+        let mut data = 0u8;
+        for n in 0..self.bpp() {
+            let byte = ((n >> 1) << 4) + (n & 1);
+            let offset = (addr + byte) & (self.ram.len() - 1);
+            self.cycles += self.mem_clocks();
+            data |= ((self.ram[offset] >> bit) & 1) << n;
+        }
+        data
+    }
+
+    fn branch(&mut self, cond: bool) {
+        let disp = self.pipe() as i8;
+        if cond {
+            let pc = self.r[15].wrapping_add(disp as u16);
+            self.set_reg(15, pc);
+        }
+    }
+
+    fn add(&mut self, op: u16, carry: bool) {
+        let sr = self.sr();
+        let r = sr as i32 + op as i32 + carry as i32;
+        self.sfr.set(SFR_OV, !(sr ^ op) & (op ^ r as u16) & 0x8000 != 0);
+        self.sfr.set(SFR_CY, r >= 0x10000);
+        self.set_sz(r as u16);
+        self.set_dr(r as u16);
+    }
+
+    fn sub(&mut self, op: u16, borrow: bool, store: bool) {
+        let sr = self.sr();
+        let r = sr as i32 - op as i32 - borrow as i32;
+        self.sfr.set(SFR_OV, (sr ^ op) & (sr ^ r as u16) & 0x8000 != 0);
+        self.sfr.set(SFR_CY, r >= 0);
+        self.set_sz(r as u16);
+        if store {
+            self.set_dr(r as u16);
+        }
+    }
+
+    fn logic(&mut self, val: u16) {
+        self.set_sz(val);
+        self.set_dr(val);
+    }
+
+    fn mult(&mut self, op: u16, signed: bool) {
+        let sr = self.sr();
+        let r = match signed {
+            true => ((sr as u8 as i8 as i16) * (op as u8 as i8 as i16)) as u16,
+            false => (sr as u8 as u16) * (op as u8 as u16),
+        };
+        self.set_sz(r);
+        self.set_dr(r);
+        if self.cfgr & CFGR_MS0 == 0 {
+            self.cycles += self.fast_clocks();
+        }
+    }
+
+    // Signed 16x16, the high word goes to the destination and
+    // LMULT keeps the low word in R4
+    fn fmult(&mut self, long: bool) {
+        let r = (self.sr() as i16 as i32) * (self.r[6] as i16 as i32);
+        if long {
+            self.set_reg(4, r as u16);
+        }
+        let high = (r >> 16) as u16;
+        self.set_dr(high);
+        self.sfr.set(SFR_S, high & 0x8000 != 0);
+        self.sfr.set(SFR_CY, r & 0x8000 != 0);
+        self.sfr.set(SFR_Z, high == 0);
+
+        let extra = if self.cfgr & CFGR_MS0 != 0 { 3 } else { 7 };
+        self.cycles += extra * self.fast_clocks();
+    }
+
+    // Runs one instruction, returns the master clocks it took
+    fn step(&mut self) -> u32 {
+        self.cycles = 0;
+
+        let opcode = self.peekpipe();
+        self.exec(opcode);
+
+        if !self.r15_modified {
+            self.r[15] = self.r[15].wrapping_add(1);
+        }
+
+        // Changing R14 starts a read into the ROM buffer
+        if self.r14_modified {
+            self.r14_modified = false;
+            self.cycles += self.mem_clocks();
+            let (bank, addr) = (self.rombr, self.r[14]);
+            self.rombuffer = self.bus_read(bank, addr);
+        }
+
+        self.cycles
+    }
+
+    fn exec(&mut self, opcode: u8) {
+        let n = (opcode & 0x0F) as usize;
+        let alt = (self.sfr & (SFR_ALT1 | SFR_ALT2)).bits() >> 8;
+
+        match opcode {
+            0x00 => {
+                // STOP
+                if self.cfgr & CFGR_IRQ == 0 {
+                    self.sfr.insert(SFR_IRQ);
+                }
+                self.sfr.remove(SFR_G);
+                self.pipeline = 0x01;
+                self.reset_prefix();
+            }
+            0x01 => self.reset_prefix(),
+            0x02 => {
+                // CACHE
+                if self.cbr != self.r[15] & 0xFFF0 {
+                    self.cbr = self.r[15] & 0xFFF0;
+                    self.cache_flush();
+                }
+                self.reset_prefix();
+            }
+            0x03 => {
+                // LSR
+                let sr = self.sr();
+                self.sfr.set(SFR_CY, sr & 1 == 1);
+                self.logic(sr >> 1);
+                self.reset_prefix();
+            }
+            0x04 => {
+                // ROL
+                let sr = self.sr();
+                let carry = self.sfr.contains(SFR_CY) as u16;
+                self.sfr.set(SFR_CY, sr & 0x8000 != 0);
+                self.logic((sr << 1) | carry);
+                self.reset_prefix();
+            }
+            0x05 => self.branch(true),
+            0x06 => {
+                let cond = self.sfr.contains(SFR_S) == self.sfr.contains(SFR_OV);
+                self.branch(cond);
+            }
+            0x07 => {
+                let cond = self.sfr.contains(SFR_S) != self.sfr.contains(SFR_OV);
+                self.branch(cond);
+            }
+            0x08 => {
+                let cond = !self.sfr.contains(SFR_Z);
+                self.branch(cond);
+            }
+            0x09 => {
+                let cond = self.sfr.contains(SFR_Z);
+                self.branch(cond);
+            }
+            0x0A => {
+                let cond = !self.sfr.contains(SFR_S);
+                self.branch(cond);
+            }
+            0x0B => {
+                let cond = self.sfr.contains(SFR_S);
+                self.branch(cond);
+            }
+            0x0C => {
+                let cond = !self.sfr.contains(SFR_CY);
+                self.branch(cond);
+            }
+            0x0D => {
+                let cond = self.sfr.contains(SFR_CY);
+                self.branch(cond);
+            }
+            0x0E => {
+                let cond = !self.sfr.contains(SFR_OV);
+                self.branch(cond);
+            }
+            0x0F => {
+                let cond = self.sfr.contains(SFR_OV);
+                self.branch(cond);
+            }
+            0x10...0x1F => {
+                // TO, or MOVE after WITH
+                if !self.sfr.contains(SFR_B) {
+                    self.dreg = n;
+                    return;
+                }
+                let sr = self.sr();
+                self.set_reg(n, sr);
+                self.reset_prefix();
+            }
+            0x20...0x2F => {
+                // WITH
+                self.sreg = n;
+                self.dreg = n;
+                self.sfr.insert(SFR_B);
+            }
+            0x30...0x3B => {
+                // STW and STB
+                self.ramaddr = self.r[n];
+                let (addr, sr) = (self.ramaddr, self.sr());
+                match alt {
+                    1 | 3 => self.ram_write(addr, sr as u8),
+                    _ => self.ram_write_u16(addr, sr),
+                }
+                self.reset_prefix();
+            }
+            0x3C => {
+                // LOOP
+                let r12 = self.r[12].wrapping_sub(1);
+                self.set_reg(12, r12);
+                self.set_sz(r12);
+                if r12 != 0 {
+                    let r13 = self.r[13];
+                    self.set_reg(15, r13);
+                }
+                self.reset_prefix();
+            }
+            0x3D => {
+                self.sfr.remove(SFR_B);
+                self.sfr.insert(SFR_ALT1);
+            }
+            0x3E => {
+                self.sfr.remove(SFR_B);
+                self.sfr.insert(SFR_ALT2);
+            }
+            0x3F => {
+                self.sfr.remove(SFR_B);
+                self.sfr.insert(SFR_ALT1 | SFR_ALT2);
+            }
+            0x40...0x4B => {
+                // LDW and LDB
+                self.ramaddr = self.r[n];
+                let addr = self.ramaddr;
+                let val = match alt {
+                    1 | 3 => self.ram_read(addr) as u16,
+                    _ => self.ram_read_u16(addr),
+                };
+                self.set_dr(val);
+                self.reset_prefix();
+            }
+            0x4C => {
+                let (x, y) = (self.r[1] as u8, self.r[2] as u8);
+                match alt {
+                    1 | 3 => {
+                        // RPIX
+                        let val = self.rpix(x, y) as u16;
+                        self.set_sz(val);
+                        self.set_dr(val);
+                    }
+                    _ => {
+                        // PLOT
+                        self.plot(x, y);
+                        let r1 = self.r[1].wrapping_add(1);
+                        self.set_reg(1, r1);
+                    }
+                }
+                self.reset_prefix();
+            }
+            0x4D => {
+                // SWAP
+                let sr = self.sr();
+                self.logic(sr.rotate_left(8));
+                self.reset_prefix();
+            }
+            0x4E => {
+                let sr = self.sr();
+                match alt {
+                    1 | 3 => self.por = sr as u8,
+                    _ => self.colr = self.color(sr as u8),
+                }
+                self.reset_prefix();
+            }
+            0x4F => {
+                // NOT
+                let sr = self.sr();
+                self.logic(!sr);
+                self.reset_prefix();
+            }
+            0x50...0x5F => {
+                // ADD, ADC, and with immediates
+                let carry = self.sfr.contains(SFR_CY);
+                match alt {
+                    0 => { let op = self.r[n]; self.add(op, false) }
+                    1 => { let op = self.r[n]; self.add(op, carry) }
+                    2 => self.add(n as u16, false),
+                    _ => self.add(n as u16, carry),
+                }
+                self.reset_prefix();
+            }
+            0x60...0x6F => {
+                // SUB, SBC, SUB with an immediate and CMP
+                let borrow = !self.sfr.contains(SFR_CY);
+                match alt {
+                    0 => { let op = self.r[n]; self.sub(op, false, true) }
+                    1 => { let op = self.r[n]; self.sub(op, borrow, true) }
+                    2 => self.sub(n as u16, false, true),
+                    _ => { let op = self.r[n]; self.sub(op, false, false) }
+                }
+                self.reset_prefix();
+            }
+            0x70 => {
+                // MERGE, the flags test the top bits of both bytes
+                let val = (self.r[7] & 0xFF00) | (self.r[8] >> 8);
+                self.set_dr(val);
+                self.sfr.set(SFR_OV, val & 0xC0C0 != 0);
+                self.sfr.set(SFR_S, val & 0x8080 != 0);
+                self.sfr.set(SFR_CY, val & 0xE0E0 != 0);
+                self.sfr.set(SFR_Z, val & 0xF0F0 != 0);
+                self.reset_prefix();
+            }
+            0x71...0x7F => {
+                // AND and BIC
+                let op = match alt {
+                    0 | 1 => self.r[n],
+                    _ => n as u16,
+                };
+                let sr = self.sr();
+                match alt {
+                    0 | 2 => self.logic(sr & op),
+                    _ => self.logic(sr & !op),
+                }
+                self.reset_prefix();
+            }
+            0x80...0x8F => {
+                // MULT and UMULT
+                let op = match alt {
+                    0 | 1 => self.r[n],
+                    _ => n as u16,
+                };
+                self.mult(op, alt & 1 == 0);
+                self.reset_prefix();
+            }
+            0x90 => {
+                // SBK
+                let (addr, sr) = (self.ramaddr, self.sr());
+                self.ram_write_u16(addr, sr);
+                self.reset_prefix();
+            }
+            0x91...0x94 => {
+                // LINK
+                let r11 = self.r[15].wrapping_add(n as u16);
+                self.set_reg(11, r11);
+                self.reset_prefix();
+            }
+            0x95 => {
+                // SEX
+                let sr = self.sr();
+                self.logic(sr as u8 as i8 as i16 as u16);
+                self.reset_prefix();
+            }
+            0x96 => {
+                // ASR, and DIV2 which rounds -1 to 0
+                let sr = self.sr();
+                self.sfr.set(SFR_CY, sr & 1 == 1);
+                let val = match alt {
+                    1 | 3 => ((sr as i16 >> 1) as u16).wrapping_add(((sr as u32 + 1) >> 16) as u16),
+                    _ => (sr as i16 >> 1) as u16,
+                };
+                self.logic(val);
+                self.reset_prefix();
+            }
+            0x97 => {
+                // ROR
+                let sr = self.sr();
+                let carry = self.sfr.contains(SFR_CY) as u16;
+                self.sfr.set(SFR_CY, sr & 1 == 1);
+                self.logic((carry << 15) | (sr >> 1));
+                self.reset_prefix();
+            }
+            0x98...0x9D => {
+                match alt {
+                    1 | 3 => {
+                        // LJMP, the bank comes from the register
+                        self.pbr = (self.r[n] & 0x7F) as u8;
+                        let sr = self.sr();
+                        self.set_reg(15, sr);
+                        self.cbr = sr & 0xFFF0;
+                        self.cache_flush();
+                    }
+                    _ => {
+                        // JMP
+                        let rn = self.r[n];
+                        self.set_reg(15, rn);
+                    }
+                }
+                self.reset_prefix();
+            }
+            0x9E => {
+                // LOB
+                let val = self.sr() & 0xFF;
+                self.set_dr(val);
+                self.sfr.set(SFR_S, val & 0x80 != 0);
+                self.sfr.set(SFR_Z, val == 0);
+                self.reset_prefix();
+            }
+            0x9F => {
+                // FMULT and LMULT
+                self.fmult(alt & 1 == 1);
+                self.reset_prefix();
+            }
+            0xA0...0xAF => {
+                match alt {
+                    1 | 3 => {
+                        // LMS, a word at twice the byte. With both ALT
+                        // Bits set ALT1 wins, for LM too
+                        self.ramaddr = (self.pipe() as u16) << 1;
+                        let addr = self.ramaddr;
+                        let val = self.ram_read_u16(addr);
+                        self.set_reg(n, val);
+                    }
+                    2 => {
+                        // SMS
+                        self.ramaddr = (self.pipe() as u16) << 1;
+                        let (addr, rn) = (self.ramaddr, self.r[n]);
+                        self.ram_write_u16(addr, rn);
+                    }
+                    _ => {
+                        // IBT
+                        let val = self.pipe() as i8 as i16 as u16;
+                        self.set_reg(n, val);
+                    }
+                }
+                self.reset_prefix();
+            }
+            0xB0...0xBF => {
+                // FROM, or MOVES after WITH
+                if !self.sfr.contains(SFR_B) {
+                    self.sreg = n;
+                    return;
+                }
+                let val = self.r[n];
+                self.set_dr(val);
+                self.sfr.set(SFR_OV, val & 0x80 != 0);
+                self.sfr.set(SFR_S, val & 0x8000 != 0);
+                self.sfr.set(SFR_Z, val == 0);
+                self.reset_prefix();
+            }
+            0xC0 => {
+                // HIB
+                let val = self.sr() >> 8;
+                self.set_dr(val);
+                self.sfr.set(SFR_S, val & 0x80 != 0);
+                self.sfr.set(SFR_Z, val == 0);
+                self.reset_prefix();
+            }
+            0xC1...0xCF => {
+                // OR and XOR
+                let op = match alt {
+                    0 | 1 => self.r[n],
+                    _ => n as u16,
+                };
+                let sr = self.sr();
+                match alt {
+                    0 | 2 => self.logic(sr | op),
+                    _ => self.logic(sr ^ op),
+                }
+                self.reset_prefix();
+            }
+            0xD0...0xDE => {
+                // INC
+                let val = self.r[n].wrapping_add(1);
+                self.set_reg(n, val);
+                self.set_sz(val);
+                self.reset_prefix();
+            }
+            0xDF => {
+                let sr = self.sr();
+                match alt {
+                    // RAMB
+                    2 => self.rambr = (sr & 0x01) as u8,
+                    // ROMB
+                    3 => self.rombr = (sr & 0x7F) as u8,
+                    // GETC
+                    _ => {
+                        let rombuffer = self.rombuffer;
+                        self.colr = self.color(rombuffer);
+                    }
+                }
+                self.reset_prefix();
+            }
+            0xE0...0xEE => {
+                // DEC
+                let val = self.r[n].wrapping_sub(1);
+                self.set_reg(n, val);
+                self.set_sz(val);
+                self.reset_prefix();
+            }
+            0xEF => {
+                // GETB, GETBH, GETBL and GETBS
+                let (sr, byte) = (self.sr(), self.rombuffer as u16);
+                let val = match alt {
+                    0 => byte,
+                    1 => (byte << 8) | (sr & 0x00FF),
+                    2 => (sr & 0xFF00) | byte,
+                    _ => byte as u8 as i8 as i16 as u16,
+                };
+                self.set_dr(val);
+                self.reset_prefix();
+            }
+            _ => {
+                let lo = self.pipe() as u16;
+                match alt {
+                    1 | 3 => {
+                        // LM
+                        self.ramaddr = lo | ((self.pipe() as u16) << 8);
+                        let addr = self.ramaddr;
+                        let val = self.ram_read_u16(addr);
+                        self.set_reg(n, val);
+                    }
+                    2 => {
+                        // SM
+                        self.ramaddr = lo | ((self.pipe() as u16) << 8);
+                        let (addr, rn) = (self.ramaddr, self.r[n]);
+                        self.ram_write_u16(addr, rn);
+                    }
+                    _ => {
+                        // IWT
+                        let val = lo | ((self.pipe() as u16) << 8);
+                        self.set_reg(n, val);
+                    }
+                }
+                self.reset_prefix();
+            }
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.sfr.contains(SFR_G)
+    }
+
+    fn cpu_register_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x3000...0x301F => {
+                let r = self.r[((addr >> 1) & 0xF) as usize];
+                if addr & 1 == 0 { r as u8 } else { (r >> 8) as u8 }
+            }
+            0x3030 => self.sfr.bits() as u8,
+            0x3031 => {
+                // Reading the high byte acknowledges the IRQ
+                let val = (self.sfr.bits() >> 8) as u8;
+                self.sfr.remove(SFR_IRQ);
+                val
+            }
+            0x3034 => self.pbr,
+            0x3036 => self.rombr,
+            0x303B => if self.gsu2 { 0x04 } else { 0x01 },
+            0x303C => self.rambr,
+            0x303E => self.cbr as u8,
+            0x303F => (self.cbr >> 8) as u8,
+            0x3100...0x32FF => self.cache[((addr - 0x3100) as usize + self.cbr as usize) & (CACHE_SIZE - 1)],
+            _ => 0u8,
+        }
+    }
+
+    fn cpu_register_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x3000...0x301F => {
+                let n = ((addr >> 1) & 0xF) as usize;
+                self.r[n] = match addr & 1 {
+                    0 => (self.r[n] & 0xFF00) | val as u16,
+                    _ => ((val as u16) << 8) | (self.r[n] & 0x00FF),
+                };
+                if n == 14 {
+                    self.r14_modified = true;
+                }
+                // Writing the high byte of R15 starts the GSU
+                if addr == 0x301F {
+                    self.sfr.insert(SFR_G);
+                }
+            }
+            0x3030 => {
+                let running = self.running();
+                self.sfr = Sfr::from_bits_truncate((self.sfr.bits() & 0xFF00) | val as u16);
+                if running && !self.running() {
+                    self.cbr = 0;
+                    self.cache_flush();
+                }
+            }
+            0x3031 => self.sfr = Sfr::from_bits_truncate(((val as u16) << 8) | (self.sfr.bits() & 0x00FF)),
+            0x3033 => self.bramr = val & 0x01,
+            0x3034 => {
+                self.pbr = val & 0x7F;
+                self.cache_flush();
+            }
+            0x3037 => self.cfgr = val,
+            0x3038 => self.scbr = val,
+            // The GSU-1 only runs at 10.7 MHz
+            0x3039 => self.clsr = if self.gsu2 { val & 0x01 } else { 0 },
+            0x303A => self.scmr = val,
+            0x3100...0x32FF => {
+                let offset = ((addr - 0x3100) as usize + self.cbr as usize) & (CACHE_SIZE - 1);
+                self.cache[offset] = val;
+                if offset & 15 == 15 {
+                    self.cache_valid[offset >> 4] = true;
+                }
+            }
+            _ => { }
+        }
+    }
+}
+
+impl Coprocessor for SuperFx {
+    fn name(&self) -> &'static str {
+        if self.gsu2 { "Super FX GSU-2" } else { "Super FX GSU-1" }
+    }
+
+    fn reset(&mut self) {
+        self.power();
+    }
+
+    fn run(&mut self, clocks: u32) {
+        self.clock_debt += clocks as i64;
+        while self.clock_debt > 0 {
+            // Stopped, or waiting for the CPU to give the bus back
+            let code_in_rom = self.pbr <= 0x5F;
+            if !self.running() || (code_in_rom && self.scmr & SCMR_RON == 0)
+                || (!code_in_rom && self.scmr & SCMR_RAN == 0) {
+                self.clock_debt = 0;
+                break;
+            }
+
+            let cycles = self.step();
+            self.clock_debt -= cycles as i64;
+        }
+    }
+
+    // ROM in banks $00-$3F and $40-$5F and their mirrors, RAM in
+    // $70-$71 and its first 8K at $6000 in the low banks
+    fn read(&mut self, addr: u16, bank: u8, open_bus: u8) -> Option<u8> {
+        let bank = bank & 0x7F;
+        let rom_locked = self.running() && self.scmr & SCMR_RON != 0;
+        let ram_locked = self.running() && self.scmr & SCMR_RAN != 0;
+
+        match (bank, addr) {
+            (0x00...0x3F, 0x3000...0x34FF) => Some(self.cpu_register_read(addr)),
+            (0x00...0x3F, 0x6000...0x7FFF) => {
+                let offset = (addr - 0x6000) as usize & (self.ram.len() - 1);
+                Some(if ram_locked { open_bus } else { self.ram[offset] })
+            }
+            (0x00...0x3F, 0x8000...0xFFFF) | (0x40...0x5F, _) => {
+                Some(if rom_locked { ROM_VECTORS[(addr & 0xF) as usize] } else { self.rom_read(bank, addr) })
+            }
+            (0x70...0x71, _) => {
+                let offset = self.ram_offset(bank, addr);
+                Some(if ram_locked { open_bus } else { self.ram[offset] })
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, addr: u16, bank: u8, val: u8) -> bool {
+        let bank = bank & 0x7F;
+        let ram_locked = self.running() && self.scmr & SCMR_RAN != 0;
+
+        match (bank, addr) {
+            (0x00...0x3F, 0x3000...0x34FF) => {
+                self.cpu_register_write(addr, val);
+                true
+            }
+            (0x00...0x3F, 0x6000...0x7FFF) => {
+                if !ram_locked {
+                    let offset = (addr - 0x6000) as usize & (self.ram.len() - 1);
+                    self.ram[offset] = val;
+                }
+                true
+            }
+            // ROM ignores writes
+            (0x00...0x3F, 0x8000...0xFFFF) | (0x40...0x5F, _) => true,
+            (0x70...0x71, _) => {
+                if !ram_locked {
+                    let offset = self.ram_offset(bank, addr);
+                    self.ram[offset] = val;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.sfr.contains(SFR_IRQ)
+    }
+
+    fn box_clone(&self) -> Box<Coprocessor + Send> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coprocessor::Coprocessor;
+
+    fn gsu() -> SuperFx {
+        SuperFx::new(vec![0u8; 0x8000], false)
+    }
+
+    // Plots a few rows, some partial and some a whole character
+    // Wide, and reads every pixel back
+    fn round_trip(scmr: u8, por: u8, mask: u8, points: &[(u8, u8)]) {
+        let mut gsu = gsu();
+        gsu.scmr = scmr;
+        gsu.por = por | POR_TRANSPARENT;
+
+        let color = |x: u8, y: u8| (x.wrapping_mul(37) ^ y.wrapping_mul(11)) & mask;
+        for &(x, y) in points {
+            gsu.colr = color(x, y);
+            gsu.plot(x, y);
+        }
+        for &(x, y) in points {
+            assert_eq!(gsu.rpix(x, y), color(x, y), "pixel {},{}", x, y);
+        }
+    }
+
+    fn points(max_y: u8) -> Vec<(u8, u8)> {
+        let mut points = Vec::new();
+        for x in 0..20 {
+            points.push((x, 3));
+        }
+        for &(x, y) in &[(100, 7), (101, 8), (255, max_y), (17, max_y / 2)] {
+            points.push((x, y));
+        }
+        points
+    }
+
+    #[test]
+    fn plot_2bpp() {
+        round_trip(0, 0, 0x03, &points(127));
+    }
+
+    #[test]
+    fn plot_4bpp() {
+        round_trip(0x01, 0, 0x0F, &points(127));
+    }
+
+    #[test]
+    fn plot_8bpp() {
+        round_trip(0x03, 0, 0xFF, &points(127));
+    }
+
+    #[test]
+    fn plot_obj() {
+        round_trip(0x01, POR_OBJ, 0x0F, &points(255));
+    }
+
+    #[test]
+    fn fmult_flags() {
+        let mut gsu = gsu();
+        gsu.r[0] = 0xFFFE;
+        gsu.r[6] = 0x4000;
+        gsu.exec(0x9F);
+        assert_eq!(gsu.r[0], 0xFFFF);
+        assert!(gsu.sfr.contains(SFR_S | SFR_CY));
+        assert!(!gsu.sfr.contains(SFR_Z));
+
+        // LMULT keeps the low word in R4
+        gsu.r[0] = 0x0002;
+        gsu.sfr.insert(SFR_ALT1);
+        gsu.exec(0x9F);
+        assert_eq!(gsu.r[0], 0x0000);
+        assert_eq!(gsu.r[4], 0x8000);
+        assert!(gsu.sfr.contains(SFR_Z | SFR_CY));
+        assert!(!gsu.sfr.contains(SFR_S));
+    }
+
+    #[test]
+    fn div2_minus_one() {
+        let mut gsu = gsu();
+        gsu.r[0] = 0xFFFF;
+        gsu.exec(0x96);
+        assert_eq!(gsu.r[0], 0xFFFF);
+
+        gsu.r[0] = 0xFFFF;
+        gsu.sfr.insert(SFR_ALT1);
+        gsu.exec(0x96);
+        assert_eq!(gsu.r[0], 0x0000);
+        assert!(gsu.sfr.contains(SFR_Z | SFR_CY));
+        assert!(!gsu.sfr.contains(SFR_S));
+    }
+
+    #[test]
+    fn sbc_cmp_flags() {
+        let mut gsu = gsu();
+
+        // SBC borrows when carry is clear
+        gsu.r[0] = 5;
+        gsu.r[1] = 5;
+        gsu.sfr.insert(SFR_ALT1);
+        gsu.exec(0x61);
+        assert_eq!(gsu.r[0], 0xFFFF);
+        assert!(gsu.sfr.contains(SFR_S));
+        assert!(!gsu.sfr.contains(SFR_CY | SFR_Z));
+
+        gsu.r[0] = 5;
+        gsu.sfr.insert(SFR_ALT1 | SFR_CY);
+        gsu.exec(0x61);
+        assert_eq!(gsu.r[0], 0);
+        assert!(gsu.sfr.contains(SFR_Z | SFR_CY));
+
+        // CMP only sets the flags
+        gsu.r[0] = 0x8000;
+        gsu.r[1] = 1;
+        gsu.sfr.insert(SFR_ALT1 | SFR_ALT2);
+        gsu.exec(0x61);
+        assert_eq!(gsu.r[0], 0x8000);
+        assert!(gsu.sfr.contains(SFR_OV | SFR_CY));
+        assert!(!gsu.sfr.contains(SFR_S | SFR_Z));
+    }
+
+    #[test]
+    fn merge_flags() {
+        let mut gsu = gsu();
+        gsu.r[7] = 0x8012;
+        gsu.r[8] = 0x4034;
+        gsu.exec(0x70);
+        assert_eq!(gsu.r[0], 0x8040);
+        assert!(gsu.sfr.contains(SFR_OV | SFR_S | SFR_CY | SFR_Z));
+
+        gsu.r[7] = 0x0100;
+        gsu.r[8] = 0x0200;
+        gsu.exec(0x70);
+        assert_eq!(gsu.r[0], 0x0102);
+        assert!(!gsu.sfr.intersects(SFR_OV | SFR_S | SFR_CY | SFR_Z));
+    }
+
+    // A line written through $3100 is valid and runs from the
+    // Cache, the ROM under it only holds STOPs
+    #[test]
+    fn cache_fill() {
+        let mut gsu = gsu();
+        let program = [0xF0, 0x34, 0x12, 0x00, 0x01, 0x01, 0x01, 0x01,
+                       0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01];
+        for (i, &byte) in program.iter().enumerate() {
+            gsu.write(0x3100 + i as u16, 0x00, byte);
+        }
+        assert!(gsu.cache_valid[0]);
+        assert!(!gsu.cache_valid[1]);
+        assert_eq!(gsu.read(0x3101, 0x00, 0), Some(0x34));
+
+        gsu.write(0x303A, 0x00, SCMR_RON | SCMR_RAN);
+        gsu.write(0x301E, 0x00, 0x00);
+        gsu.write(0x301F, 0x00, 0x00);
+        gsu.run(1000);
+
+        assert_eq!(gsu.r[0], 0x1234);
+        assert!(gsu.irq());
+    }
+}
@@ -0,0 +1,251 @@
+use std::cmp;
+
+// Master clocks per scanline, every dot takes 4 clocks except
+// Dots 323 and 327 which take 6
+pub const LINE_CLOCKS: u32 = 1364;
+
+// Positions of the events inside a line, in master clocks
+const HDMA_INIT_CLOCK: u32 = 24;
+const AUTO_JOYPAD_CLOCK: u32 = 130;
+const RENDER_CLOCK: u32 = 1096;
+const HDMA_CLOCK: u32 = 1104;
+
+// The auto-joypad read keeps the bus busy for about 3 lines
+const AUTO_JOYPAD_CLOCKS: u32 = 4224;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    NewFrame,       // V-blank ends, the field flips
+    HdmaInit,       // HDMA channels reload their tables
+    Render(u16),    // A visible line has been drawn
+    HBlank,         // HDMA transfers for the next line
+    VBlank,         // V-blank starts, NMI
+    AutoJoypad,     // The auto-joypad read starts
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timing {
+    pub hclock: u32,
+    pub vcounter: u16,
+    pub field: bool,
+    pub pal: bool,
+    pub interlace: bool,
+    pub overscan: bool,
+}
+
+impl Timing {
+    pub fn reset(&mut self) {
+        self.hclock = 0u32;
+        self.vcounter = 0u16;
+        self.field = false;
+    }
+
+    // Horizontal position in dots
+    pub fn hcounter(&self) -> u16 {
+        let clock = self.hclock;
+
+        // The short line has no long dots
+        if self.line_clocks() == LINE_CLOCKS - 4 {
+            return (clock / 4) as u16;
+        }
+
+        let dot = match clock {
+            0...1291 => clock / 4,
+            1292...1297 => 323,
+            1298...1309 => 324 + (clock - 1298) / 4,
+            1310...1315 => 327,
+            _ => 328 + (clock - 1316) / 4,
+        };
+
+        dot as u16
+    }
+
+    // NTSC drops 4 clocks from line 240 of every other
+    // Non-interlaced frame, PAL adds 4 to line 311 of
+    // Every other interlaced frame
+    pub fn line_clocks(&self) -> u32 {
+        match (self.pal, self.interlace, self.field, self.vcounter) {
+            (false, false, true, 240) => LINE_CLOCKS - 4,
+            (true, true, true, 311) => LINE_CLOCKS + 4,
+            _ => LINE_CLOCKS,
+        }
+    }
+
+    // 262 lines for NTSC and 312 for PAL, one more
+    // On the even field when interlacing
+    pub fn lines(&self) -> u16 {
+        let lines = if self.pal { 312 } else { 262 };
+        match self.interlace && !self.field {
+            true => lines + 1,
+            false => lines,
+        }
+    }
+
+    // V-blank starts after the last visible line,
+    // Line 225 normally or 240 with overscan
+    pub fn vblank_line(&self) -> u16 {
+        if self.overscan { 240 } else { 225 }
+    }
+
+    // The flag drops at the start of line 0, which the PPU
+    // Already spends getting ready for line 1
+    pub fn vblank(&self) -> bool {
+        self.vcounter >= self.vblank_line()
+    }
+
+    // H-blank covers the right border and the left border
+    pub fn hblank(&self) -> bool {
+        let hcounter = self.hcounter();
+        hcounter <= 1 || hcounter >= 274
+    }
+
+    pub fn auto_joypad_busy(&self) -> bool {
+        let start = self.vblank_line();
+        if self.vcounter < start {
+            return false;
+        }
+
+        let clock = (self.vcounter - start) as u32 * LINE_CLOCKS + self.hclock;
+        clock >= AUTO_JOYPAD_CLOCK && clock < AUTO_JOYPAD_CLOCK + AUTO_JOYPAD_CLOCKS
+    }
+
+    fn next_event_clock(&self, line_clocks: u32) -> u32 {
+        let visible = self.vblank_line() - 1;
+        let mut next = line_clocks;
+
+        if self.vcounter == 0 && self.hclock < HDMA_INIT_CLOCK {
+            next = cmp::min(next, HDMA_INIT_CLOCK);
+        }
+        if self.vcounter == visible + 1 && self.hclock < AUTO_JOYPAD_CLOCK {
+            next = cmp::min(next, AUTO_JOYPAD_CLOCK);
+        }
+        if self.vcounter >= 1 && self.vcounter <= visible && self.hclock < RENDER_CLOCK {
+            next = cmp::min(next, RENDER_CLOCK);
+        }
+        if self.vcounter <= visible && self.hclock < HDMA_CLOCK {
+            next = cmp::min(next, HDMA_CLOCK);
+        }
+
+        next
+    }
+
+    // Advance the beam by up to `clocks` master clocks, stopping at the
+    // First event on the way. Returns the clocks used and the event.
+    pub fn advance(&mut self, clocks: u32) -> (u32, Option<Event>) {
+        let line_clocks = self.line_clocks();
+        let next = self.next_event_clock(line_clocks);
+        let step = cmp::min(clocks, next - self.hclock);
+
+        self.hclock += step;
+
+        if self.hclock < next {
+            return (step, None);
+        }
+
+        if self.hclock >= line_clocks {
+            self.hclock = 0;
+            self.vcounter += 1;
+
+            if self.vcounter >= self.lines() {
+                self.vcounter = 0;
+                self.field = !self.field;
+                return (step, Some(Event::NewFrame));
+            }
+
+            if self.vcounter == self.vblank_line() {
+                return (step, Some(Event::VBlank));
+            }
+
+            return (step, None);
+        }
+
+        let event = match self.hclock {
+            HDMA_INIT_CLOCK => Event::HdmaInit,
+            AUTO_JOYPAD_CLOCK => Event::AutoJoypad,
+            RENDER_CLOCK => Event::Render(self.vcounter),
+            _ => Event::HBlank,
+        };
+
+        (step, Some(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every event of one frame with the line and clock it fired at
+    fn frame(timing: &mut Timing) -> Vec<(u16, u32, Event)> {
+        let mut events = Vec::new();
+        loop {
+            let (line, clock) = (timing.vcounter, timing.hclock);
+            let (used, event) = timing.advance(1000);
+            if let Some(event) = event {
+                events.push((line, clock + used, event));
+                if event == Event::NewFrame {
+                    return events;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn event_positions() {
+        let mut timing = Timing::default();
+        let events = frame(&mut timing);
+
+        assert_eq!(events[0], (0, HDMA_INIT_CLOCK, Event::HdmaInit));
+        assert_eq!(events[1], (0, HDMA_CLOCK, Event::HBlank));
+        assert_eq!(events[2], (1, RENDER_CLOCK, Event::Render(1)));
+        assert_eq!(events[3], (1, HDMA_CLOCK, Event::HBlank));
+
+        // The line ends as V-blank starts
+        let vblank = events.iter().position(|e| e.2 == Event::VBlank).unwrap();
+        assert_eq!(events[vblank], (224, LINE_CLOCKS, Event::VBlank));
+        assert_eq!(events[vblank + 1], (225, AUTO_JOYPAD_CLOCK, Event::AutoJoypad));
+        assert_eq!(events[vblank + 2], (261, LINE_CLOCKS, Event::NewFrame));
+    }
+
+    #[test]
+    fn event_counts() {
+        let mut timing = Timing::default();
+        let events = frame(&mut timing);
+
+        let count = |f: &Fn(Event) -> bool| events.iter().filter(|e| f(e.2)).count();
+        assert_eq!(count(&|e| match e { Event::Render(_) => true, _ => false }), 224);
+        assert_eq!(count(&|e| e == Event::HBlank), 225);
+        assert_eq!(count(&|e| e == Event::HdmaInit), 1);
+    }
+
+    #[test]
+    fn vblank_flag() {
+        let mut timing = Timing::default();
+        assert!(!timing.vblank());
+        timing.vcounter = 224;
+        assert!(!timing.vblank());
+        timing.vcounter = 225;
+        assert!(timing.vblank());
+        timing.overscan = true;
+        assert!(!timing.vblank());
+    }
+
+    // NTSC frames alternate between 262 full lines and one
+    // With a line 4 clocks short
+    #[test]
+    fn short_line() {
+        let mut timing = Timing::default();
+        let mut clocks = [0u32; 2];
+        for n in 0..2 {
+            loop {
+                let (used, event) = timing.advance(LINE_CLOCKS);
+                clocks[n] += used;
+                if event == Some(Event::NewFrame) {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(clocks[0], 262 * LINE_CLOCKS);
+        assert_eq!(clocks[1], 262 * LINE_CLOCKS - 4);
+    }
+}